@@ -0,0 +1,148 @@
+//! Sanity checks over a `Definition`. Guards are ignored: a transition is
+//! considered takeable as soon as it has been declared. Being in a substate
+//! means being in every composite state containing it as well.
+
+use Definition;
+
+impl<'a, S: Eq + Clone, E: Eq + Clone, C> Definition<'a, S, E, C> {
+
+    /// Every state that can be reached from the initial state, in the order
+    /// they are discovered.
+    pub fn reachable_states(&self) -> ~[S] {
+        let mut reachable = ~[];
+        self.reach(self.initialState.clone(), &mut reachable);
+        let mut index = 0;
+        while index < reachable.len() {
+            let state = reachable[index].clone();
+            for rule in self.rules.iter() {
+                if rule.transition.from == state {
+                    self.reach(rule.transition.to.clone(), &mut reachable);
+                }
+            }
+            for join in self.joins.iter() {
+                if *join.first_ref() == state {
+                    self.reach(join.second_ref().clone(), &mut reachable);
+                }
+            }
+            index += 1;
+        }
+        reachable
+    }
+
+    /// Add the states a transition targeting `state` enters to `reachable`:
+    /// the leaves it ends up in, one per region, and every composite state
+    /// containing them. A history pseudo-state leads into its composite
+    /// state.
+    fn reach(&self, state: S, reachable: &mut ~[S]) {
+        let state = match self.history_of(&state) {
+            Some((parent, _)) => {
+                if !reachable.contains(&state) {
+                    reachable.push(state.clone());
+                }
+                parent.clone()
+            }
+            None => state
+        };
+        for state in self.entry([state], &None).move_iter() {
+            if !reachable.contains(&state) {
+                reachable.push(state);
+            }
+        }
+    }
+
+    /// Known states that no sequence of events leads to from the initial
+    /// state.
+    pub fn unreachable_states(&self) -> ~[S] {
+        let reachable = self.reachable_states();
+        self.states.iter()
+            .filter(|state| !reachable.contains(*state))
+            .map(|state| state.clone())
+            .collect()
+    }
+
+    /// Known leaf states that are neither final nor history pseudo-states but
+    /// have no outgoing transitions, neither of their own nor of a composite
+    /// state containing them: a machine entering one of them is stuck there,
+    /// at least in its region.
+    pub fn dead_end_states(&self) -> ~[S] {
+        self.states.iter()
+            .filter(|state| {
+                !self.finals.contains(*state) && !self.is_composite(*state) &&
+                self.history_of(*state).is_none() &&
+                !self.lineage(*state).iter().any(|s| {
+                    self.rules.iter().any(|rule| rule.transition.from == *s)
+                })
+            })
+            .map(|state| state.clone())
+            .collect()
+    }
+
+    /// Known events that no transition accepts.
+    pub fn unused_events(&self) -> ~[E] {
+        self.events.iter()
+            .filter(|event| {
+                !self.rules.iter().any(|rule| rule.transition.event == **event)
+            })
+            .map(|event| event.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Definition;
+
+    #[test]
+    fn test_sound_definition() {
+        defstates! (State -> Locked, Unlocked);
+        defstates! (Event -> Coin, Push);
+
+        let mut def: Definition<State::State, Event::State, ()> =
+            Definition::new(State::Locked);
+        def.add_transition(State::Locked, Event::Coin, State::Unlocked);
+        def.add_transition(State::Unlocked, Event::Push, State::Locked);
+
+        assert!(def.unreachable_states().is_empty());
+        assert!(def.dead_end_states().is_empty());
+        assert!(def.unused_events().is_empty());
+    }
+
+    #[test]
+    fn test_definition_mistakes() {
+        defstates! (State -> Idle, Running, Stuck, Orphan, Done);
+        defstates! (Event -> Start, Jam, Finish, Reset);
+
+        let mut def: Definition<State::State, Event::State, ()> =
+            Definition::new(State::Idle);
+        def.add_event(Event::Reset);
+        def.add_final(State::Done);
+        def.add_transition(State::Idle, Event::Start, State::Running);
+        def.add_transition(State::Running, Event::Jam, State::Stuck);
+        def.add_transition(State::Running, Event::Finish, State::Done);
+        def.add_transition(State::Orphan, Event::Start, State::Idle);
+
+        assert_eq!(def.reachable_states(),
+                   ~[State::Idle, State::Running, State::Stuck, State::Done]);
+        assert_eq!(def.unreachable_states(), ~[State::Orphan]);
+        assert_eq!(def.dead_end_states(), ~[State::Stuck]);
+        assert_eq!(def.unused_events(), ~[Event::Reset]);
+    }
+
+    #[test]
+    fn test_nested_definition() {
+        defstates! (State -> Off, Running, Heating, Cooling, Idle);
+        defstates! (Event -> Start, Warm, Stop);
+
+        let mut def: Definition<State::State, Event::State, ()> =
+            Definition::new(State::Off);
+        def.add_substate(State::Running, State::Heating);
+        def.add_substate(State::Running, State::Cooling);
+        def.add_substate(State::Running, State::Idle);
+        def.add_transition(State::Off, Event::Start, State::Running);
+        def.add_transition(State::Heating, Event::Warm, State::Cooling);
+        def.add_transition(State::Running, Event::Stop, State::Off);
+
+        assert_eq!(def.unreachable_states(), ~[State::Idle]);
+        assert!(def.dead_end_states().is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+//! Guards and actions that wait on something else, such as a database
+//! query running in another task. They return an `extra::future::Future`
+//! instead of their result.
+//!
+//! `fire_async` starts the async guards of every transition the event may
+//! take and returns a `PendingFire`. Nothing else happens until `.wait` is
+//! called: it waits for the guards, then for the async action of the
+//! transition selected, and only then moves the machine, in one step that
+//! runs exit hooks, actions and entry hooks. Dropping a `PendingFire`
+//! cancels the event and leaves the machine exactly as it was.
+
+use extra::future::Future;
+
+use Definition;
+use Rule;
+use StateMachine;
+use Transition;
+use TransitionError;
+
+/// An event fired with `fire_async` that has not been processed yet. It
+/// borrows the machine, so no other event can be fired in the meantime.
+pub struct PendingFire<'m, 'a, S, E, C> {
+    machine: &'m mut StateMachine<'a, S, E, C>,
+    event: E,
+    /// The index of every rule whose async guard was started, with its
+    /// future verdict
+    verdicts: ~[(uint, Future<bool>)]
+}
+
+impl<'m, 'a, S: Eq + Clone, E: Eq + Clone, C> PendingFire<'m, 'a, S, E, C> {
+
+    /// Wait for the async guards, then take the transition as `fire` does.
+    pub fn wait(self) -> Result<S, TransitionError<S, E>> {
+        let PendingFire { machine, event, verdicts } = self;
+        let verdicts: ~[(uint, bool)] = verdicts.move_iter()
+            .map(|(index, verdict)| (index, verdict.unwrap()))
+            .collect();
+        machine.step(event, true, verdicts)
+    }
+}
+
+impl<'a, S: Eq + Clone, E: Eq + Clone, C> Definition<'a, S, E, C> {
+
+    /// Declare a transition that may only be taken once the future returned
+    /// by `guard` resolves to `true`. The machine stays in the `from` state
+    /// until then.
+    pub fn add_async_guarded_transition(&mut self, from: S, event: E, to: S,
+                                        guard: 'a |&C| -> Future<bool>) {
+        let mut rule = Rule::new(from, event, to);
+        rule.asyncGuard = Some(guard);
+        self.push_rule(rule);
+    }
+
+    /// Declare a transition that runs `action` every time it is taken and
+    /// waits for the future it returns before leaving the `from` state.
+    pub fn add_async_transition_action(&mut self, from: S, event: E, to: S,
+                                       action: 'a |&Transition<S, E>, &C| -> Future<()>) {
+        let mut rule = Rule::new(from, event, to);
+        rule.asyncAction = Some(action);
+        self.push_rule(rule);
+    }
+}
+
+impl<'a, S: Eq + Clone, E: Eq + Clone, C> StateMachine<'a, S, E, C> {
+
+    /// Declare a transition with an async guard. See
+    /// `Definition::add_async_guarded_transition`.
+    pub fn add_async_guarded_transition(&mut self, from: S, event: E, to: S,
+                                        guard: 'a |&C| -> Future<bool>) {
+        self.definition.add_async_guarded_transition(from, event, to, guard);
+    }
+
+    /// Declare a transition with an async action. See
+    /// `Definition::add_async_transition_action`.
+    pub fn add_async_transition_action(&mut self, from: S, event: E, to: S,
+                                       action: 'a |&Transition<S, E>, &C| -> Future<()>) {
+        self.definition.add_async_transition_action(from, event, to, action);
+    }
+
+    /// Fire an event whose async guards run concurrently with the caller.
+    /// The guards of every transition declared for `event` out of a state
+    /// the machine is in are started now, against the current context; the
+    /// machine only moves once `.wait` is called on the result.
+    pub fn fire_async<'m>(&'m mut self, event: E) -> PendingFire<'m, 'a, S, E, C> {
+        let active = self.active_states();
+        let mut verdicts = ~[];
+        for (index, rule) in self.definition.rules.iter().enumerate() {
+            if rule.transition.event != event || !active.contains(&rule.transition.from) {
+                continue;
+            }
+            match rule.asyncGuard {
+                Some(ref guard) => verdicts.push((index, (*guard)(&self.context))),
+                None => ()
+            }
+        }
+        PendingFire { machine: self, event: event, verdicts: verdicts }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use extra::future::Future;
+
+    use Definition;
+    use StateMachine;
+
+    defstates! (State -> Pending, Approved, Rejected)
+    defstates! (Event -> Approve, Reject)
+
+    #[test]
+    fn test_async_guard() {
+        let mut def = Definition::new(State::Pending);
+        def.add_async_guarded_transition(State::Pending, Event::Approve,
+                                         State::Approved, |amount| {
+            let amount = *amount;
+            Future::spawn(proc() { amount < 100 })
+        });
+        def.add_transition(State::Pending, Event::Reject, State::Rejected);
+
+        let mut sm = StateMachine::from_definition(def, 250);
+        assert!(sm.fire_async(Event::Approve).wait().is_err());
+        assert_eq!(*sm.state(), State::Pending);
+
+        *sm.context_mut() = 50;
+        assert_eq!(sm.fire_async(Event::Approve).wait().ok(), Some(State::Approved));
+    }
+
+    #[test]
+    fn test_cancelled() {
+        let mut def = Definition::new(State::Pending);
+        def.add_async_guarded_transition(State::Pending, Event::Approve,
+                                         State::Approved,
+                                         |_| Future::from_value(true));
+
+        let mut sm = StateMachine::from_definition(def, ());
+        {
+            // Dropped without waiting for the guard.
+            let _pending = sm.fire_async(Event::Approve);
+        }
+        assert_eq!(*sm.state(), State::Pending);
+        assert_eq!(sm.fire_async(Event::Approve).wait().ok(), Some(State::Approved));
+    }
+
+    #[test]
+    fn test_async_action() {
+        let done = Cell::new(false);
+
+        let mut def = Definition::new(State::Pending);
+        def.add_async_transition_action(State::Pending, Event::Approve,
+                                        State::Approved, |_, _| {
+            done.set(true);
+            Future::from_value(())
+        });
+
+        let mut sm = StateMachine::from_definition(def, ());
+        assert_eq!(sm.fire(Event::Approve).ok(), Some(State::Approved));
+        assert!(done.get());
+    }
+}
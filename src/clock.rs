@@ -0,0 +1,69 @@
+//! Where machines read the time from. Every time-related feature goes
+//! through a `Clock`, so the system clock can be swapped for one that tests
+//! control.
+
+use std::cell::Cell;
+use extra::time;
+
+/// A source of time, in milliseconds.
+pub trait Clock {
+    /// The current time, in milliseconds since a fixed origin.
+    fn now(&self) -> u64;
+}
+
+/// The system clock, counting milliseconds since the epoch.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        let now = time::get_time();
+        (now.sec as u64) * 1000 + (now.nsec as u64) / 1000000
+    }
+}
+
+/// The clock machines use unless they are given another one.
+pub static SYSTEM: SystemClock = SystemClock;
+
+/// A clock that only moves when told to, so time-dependent behaviour can be
+/// tested deterministically.
+pub struct ManualClock {
+    now: Cell<u64>
+}
+
+impl ManualClock {
+    /// A clock stopped at `now`.
+    pub fn new(now: u64) -> ManualClock {
+        ManualClock { now: Cell::new(now) }
+    }
+
+    /// Move the clock `by` milliseconds forward.
+    pub fn advance(&self, by: u64) {
+        self.now.set(self.now.get() + by);
+    }
+
+    /// Move the clock to `now`, which may be in the past.
+    pub fn set(&self, now: u64) {
+        self.now.set(now);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Clock, ManualClock};
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(100);
+        assert_eq!(clock.now(), 100);
+        clock.advance(50);
+        assert_eq!(clock.now(), 150);
+        clock.set(10);
+        assert_eq!(clock.now(), 10);
+    }
+}
@@ -0,0 +1,432 @@
+//! Render a `Definition` as a diagram, so drawings of a machine are
+//! generated from the same code that runs it.
+
+use Deep;
+use Definition;
+use Rule;
+use Shallow;
+use StateMachine;
+
+/// Quote `name` as a DOT identifier.
+fn quote(name: &str) -> ~str {
+    format!("\"{}\"", name.replace("\\", "\\\\").replace("\"", "\\\""))
+}
+
+/// The name of the DOT cluster drawn for the composite state `name`.
+fn cluster(name: &str) -> ~str {
+    format!("cluster_{}", name)
+}
+
+/// Turn `name` into an identifier accepted by Mermaid and PlantUML.
+fn identifier(name: &str) -> ~str {
+    name.chars().map(|c| {
+        if c.is_alphanumeric() || c == '_' { c } else { '_' }
+    }).collect()
+}
+
+/// How a transition is labelled: its event, followed by the name of its
+/// guard in brackets when it has one, or by `[guard]` when its guard has no
+/// name.
+fn label<'a, S, E: ToStr, C>(rule: &Rule<'a, S, E, C>) -> ~str {
+    let event = rule.transition.event.to_str();
+    match rule.guardName {
+        Some(ref name) => format!("{} [{}]", event, *name),
+        None if rule.guard.is_some() || rule.asyncGuard.is_some() => {
+            format!("{} [guard]", event)
+        }
+        None => event
+    }
+}
+
+impl<'a, S: Eq + Clone + ToStr, E: Eq + Clone + ToStr, C> Definition<'a, S, E, C> {
+
+    /// Render the definition as a Graphviz DOT digraph. The initial state is
+    /// pointed to by an unlabelled dot, final states are drawn with a double
+    /// circle, history pseudo-states as a circle labelled `H` or `H*`,
+    /// composite states are drawn as clusters around their substates and
+    /// every transition is labelled with its event, followed by the name
+    /// of its guard in brackets when it has one (`[guard]` if it is
+    /// unnamed).
+    pub fn to_dot(&self) -> ~str {
+        self.render_dot([])
+    }
+
+    /// Render the definition as `to_dot` does, with `current` filled in.
+    pub fn to_dot_highlighting(&self, current: &S) -> ~str {
+        self.render_dot([current.clone()])
+    }
+
+    /// Render the definition as `to_dot` does, with the `current` states
+    /// filled in.
+    fn render_dot(&self, current: &[S]) -> ~str {
+        let mut lines = ~[~"digraph fsm {", ~"    rankdir=LR;"];
+        if !self.parents.is_empty() {
+            lines.push(~"    compound=true;");
+        }
+        lines.push(~"    __start [shape=point, label=\"\"];");
+        let (initial, lhead) = self.dot_endpoint(&self.initialState, "lhead");
+        match lhead {
+            Some(lhead) => lines.push(format!("    __start -> {} [{}];",
+                                              initial, lhead)),
+            None => lines.push(format!("    __start -> {};", initial))
+        }
+
+        self.dot_states(&None, 1, current, &mut lines);
+
+        for rule in self.rules.iter() {
+            let (tail, ltail) = self.dot_endpoint(&rule.transition.from, "ltail");
+            let (head, lhead) = self.dot_endpoint(&rule.transition.to, "lhead");
+            let mut attrs = ~[format!("label={}", quote(label(rule)))];
+            for attr in ltail.move_iter().chain(lhead.move_iter()) {
+                attrs.push(attr);
+            }
+            lines.push(format!("    {} -> {} [{}];", tail, head,
+                               attrs.connect(", ")));
+        }
+        for join in self.joins.iter() {
+            let (tail, ltail) = self.dot_endpoint(join.first_ref(), "ltail");
+            let (head, lhead) = self.dot_endpoint(join.second_ref(), "lhead");
+            let attrs: ~[~str] = ltail.move_iter().chain(lhead.move_iter()).collect();
+            if attrs.is_empty() {
+                lines.push(format!("    {} -> {};", tail, head));
+            } else {
+                lines.push(format!("    {} -> {} [{}];", tail, head,
+                                   attrs.connect(", ")));
+            }
+        }
+
+        lines.push(~"}");
+        format!("{}\n", lines.connect("\n"))
+    }
+
+    /// Render the states directly inside `parent`, or at the top level, at
+    /// the given depth. Composite states become clusters.
+    fn dot_states(&self, parent: &Option<S>, depth: uint, current: &[S],
+                  lines: &mut ~[~str]) {
+        let indent = "    ".repeat(depth);
+        for state in self.members(parent).move_iter() {
+            if self.is_composite(state) {
+                lines.push(format!("{}subgraph {} {}", indent,
+                                   quote(cluster(state.to_str())), "{"));
+                lines.push(format!("{}    label={};", indent,
+                                   quote(state.to_str())));
+                self.dot_states(&Some(state.clone()), depth + 1, current, lines);
+                lines.push(format!("{}{}", indent, "}"));
+                continue;
+            }
+
+            let mut attrs = ~[];
+            match self.history_of(state) {
+                Some((_, Shallow)) => attrs.push("shape=circle, label=\"H\""),
+                Some((_, Deep)) => attrs.push("shape=circle, label=\"H*\""),
+                None => ()
+            }
+            if self.is_final(state) {
+                attrs.push("shape=doublecircle");
+            }
+            if current.contains(state) {
+                attrs.push("style=filled, fillcolor=lightgrey");
+            }
+
+            if attrs.is_empty() {
+                lines.push(format!("{}{};", indent, quote(state.to_str())));
+            } else {
+                lines.push(format!("{}{} [{}];", indent, quote(state.to_str()),
+                                   attrs.connect(", ")));
+            }
+        }
+    }
+
+    /// The DOT node an edge to or from `state` attaches to. Composite states
+    /// are clusters, so edges attach to their initial leaf and are clipped at
+    /// the cluster through the `attr` (`lhead` or `ltail`) attribute.
+    fn dot_endpoint(&self, state: &S, attr: &str) -> (~str, Option<~str>) {
+        if self.is_composite(state) {
+            let leaf = self.initial_leaf(state.clone());
+            (quote(leaf.to_str()),
+             Some(format!("{}={}", attr, quote(cluster(state.to_str())))))
+        } else {
+            (quote(state.to_str()), None)
+        }
+    }
+
+    /// Render the definition as a Mermaid `stateDiagram-v2`, ready to be
+    /// embedded in Markdown.
+    pub fn to_mermaid(&self) -> ~str {
+        let mut lines = ~[~"stateDiagram-v2"];
+        for line in self.state_diagram().move_iter() {
+            lines.push(format!("    {}", line));
+        }
+        format!("{}\n", lines.connect("\n"))
+    }
+
+    /// Render the definition as a PlantUML state diagram.
+    pub fn to_plantuml(&self) -> ~str {
+        let mut lines = ~[~"@startuml"];
+        lines.push_all_move(self.state_diagram());
+        lines.push(~"@enduml");
+        format!("{}\n", lines.connect("\n"))
+    }
+
+    /// The body shared by Mermaid and PlantUML state diagrams, which agree
+    /// on the syntax used here.
+    fn state_diagram(&self) -> ~[~str] {
+        let mut lines = ~[];
+        self.state_diagram_block(&None, 0, &mut lines);
+        lines
+    }
+
+    /// Render the states directly inside `parent`, or at the top level, at
+    /// the given depth, followed by the transitions that stay inside it.
+    /// Composite states become nested blocks, and the regions of a parallel
+    /// state are separated by `--`. Substates, states whose name is not a
+    /// valid identifier and states no transition mentions are declared
+    /// explicitly. Joins are drawn as unlabelled transitions.
+    fn state_diagram_block(&self, parent: &Option<S>, depth: uint,
+                           lines: &mut ~[~str]) {
+        let indent = "    ".repeat(depth);
+        let parallel = match *parent {
+            Some(ref parent) => self.is_parallel(parent),
+            None => false
+        };
+
+        for (index, state) in self.members(parent).move_iter().enumerate() {
+            let name = state.to_str();
+            let id = identifier(name);
+            if parallel && index > 0 {
+                lines.push(format!("{}--", indent));
+            }
+            if self.is_composite(state) {
+                lines.push(format!("{}state {} {}", indent, id, "{"));
+                self.state_diagram_block(&Some(state.clone()), depth + 1, lines);
+                lines.push(format!("{}{}", indent, "}"));
+            } else if id != name || parent.is_some() || !self.is_mentioned(state) {
+                lines.push(format!("{}state \"{}\" as {}", indent, name, id));
+            }
+        }
+
+        if parallel {
+            return;
+        }
+        let initial = match *parent {
+            Some(ref parent) => self.initial_substate(parent).unwrap(),
+            None => &self.initialState
+        };
+        lines.push(format!("{}[*] --> {}", indent, identifier(initial.to_str())));
+        for rule in self.rules.iter() {
+            if self.domain(&rule.transition.from, &rule.transition.to) == *parent {
+                lines.push(format!("{}{} --> {} : {}", indent,
+                                   identifier(rule.transition.from.to_str()),
+                                   identifier(rule.transition.to.to_str()),
+                                   label(rule)));
+            }
+        }
+        for join in self.joins.iter() {
+            if self.domain(join.first_ref(), join.second_ref()) == *parent {
+                lines.push(format!("{}{} --> {}", indent,
+                                   identifier(join.first_ref().to_str()),
+                                   identifier(join.second_ref().to_str())));
+            }
+        }
+        for state in self.finals.iter() {
+            if self.is_member(state, parent) {
+                lines.push(format!("{}{} --> [*]", indent,
+                                   identifier(state.to_str())));
+            }
+        }
+    }
+
+    /// The states directly inside `parent`, or at the top level.
+    fn members<'b>(&'b self, parent: &Option<S>) -> ~[&'b S] {
+        self.states.iter().filter(|state| self.is_member(*state, parent)).collect()
+    }
+
+    /// Whether `state` is directly inside `parent`, or at the top level.
+    /// History pseudo-states are drawn inside their composite state.
+    fn is_member(&self, state: &S, parent: &Option<S>) -> bool {
+        let actual = match self.history_of(state) {
+            Some((owner, _)) => Some(owner),
+            None => self.parent(state)
+        };
+        match (actual, parent) {
+            (None, &None) => true,
+            (Some(actual), &Some(ref parent)) => *actual == *parent,
+            _ => false
+        }
+    }
+
+    /// Whether `state` is an initial state or the end of some transition or
+    /// join.
+    fn is_mentioned(&self, state: &S) -> bool {
+        *state == self.initialState ||
+            self.initials.iter().any(|pair| pair.second_ref() == state) ||
+            self.rules.iter().any(|rule| {
+                rule.transition.from == *state || rule.transition.to == *state
+            }) ||
+            self.joins.iter().any(|pair| {
+                pair.first_ref() == state || pair.second_ref() == state
+            })
+    }
+}
+
+impl<'a, S: Eq + Clone + ToStr, E: Eq + Clone + ToStr, C> StateMachine<'a, S, E, C> {
+
+    /// Render the definition this machine runs as a Graphviz DOT digraph,
+    /// with every active leaf state filled in.
+    pub fn to_dot(&self) -> ~str {
+        self.definition.render_dot(self.configuration.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Definition;
+
+    defstates! (State -> Locked, Unlocked, Broken)
+    defstates! (Event -> Coin, Push, Kick)
+
+    fn turnstile<'a>() -> Definition<'a, State::State, Event::State, ()> {
+        let mut def = Definition::new(State::Locked);
+        def.add_transition(State::Locked, Event::Coin, State::Unlocked);
+        def.add_named_guarded_transition(State::Unlocked, Event::Push,
+                                         State::Locked, "door closed", |_| true);
+        def.add_guarded_transition(State::Unlocked, Event::Kick, State::Broken,
+                                   |_| true);
+        def.add_final(State::Broken);
+        def
+    }
+
+    defstates! (Heater -> Off, Running, Heating, Cooling)
+    defstates! (Control -> Start, Warm, Stop)
+
+    fn heater<'a>() -> Definition<'a, Heater::State, Control::State, ()> {
+        let mut def = Definition::new(Heater::Off);
+        def.add_substate(Heater::Running, Heater::Heating);
+        def.add_substate(Heater::Running, Heater::Cooling);
+        def.add_transition(Heater::Off, Control::Start, Heater::Running);
+        def.add_transition(Heater::Heating, Control::Warm, Heater::Cooling);
+        def.add_transition(Heater::Running, Control::Stop, Heater::Off);
+        def
+    }
+
+    defstates! (Media -> Off, Player, Playback, Playing, Paused, Volume, Normal,
+                Muted)
+    defstates! (Remote -> Power, Pause, Mute)
+
+    fn player<'a>() -> Definition<'a, Media::State, Remote::State, ()> {
+        let mut def = Definition::new(Media::Off);
+        def.add_region(Media::Player, Media::Playback);
+        def.add_region(Media::Player, Media::Volume);
+        def.add_substate(Media::Playback, Media::Playing);
+        def.add_substate(Media::Playback, Media::Paused);
+        def.add_substate(Media::Volume, Media::Normal);
+        def.add_substate(Media::Volume, Media::Muted);
+        def.add_transition(Media::Off, Remote::Power, Media::Player);
+        def.add_transition(Media::Playing, Remote::Pause, Media::Paused);
+        def.add_transition(Media::Normal, Remote::Mute, Media::Muted);
+        def.add_transition(Media::Player, Remote::Power, Media::Off);
+        def
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let def = turnstile();
+        let expected = ~"digraph fsm {
+    rankdir=LR;
+    __start [shape=point, label=\"\"];
+    __start -> \"Locked\";
+    \"Locked\";
+    \"Unlocked\" [style=filled, fillcolor=lightgrey];
+    \"Broken\" [shape=doublecircle];
+    \"Locked\" -> \"Unlocked\" [label=\"Coin\"];
+    \"Unlocked\" -> \"Locked\" [label=\"Push [door closed]\"];
+    \"Unlocked\" -> \"Broken\" [label=\"Kick [guard]\"];
+}
+";
+        assert_eq!(def.to_dot_highlighting(&State::Unlocked), expected);
+        assert_eq!(def.to_dot(), expected.replace(
+            " [style=filled, fillcolor=lightgrey]", ""));
+    }
+
+    #[test]
+    fn test_nested_to_dot() {
+        assert_eq!(heater().to_dot(), ~"digraph fsm {
+    rankdir=LR;
+    compound=true;
+    __start [shape=point, label=\"\"];
+    __start -> \"Off\";
+    \"Off\";
+    subgraph \"cluster_Running\" {
+        label=\"Running\";
+        \"Heating\";
+        \"Cooling\";
+    }
+    \"Off\" -> \"Heating\" [label=\"Start\", lhead=\"cluster_Running\"];
+    \"Heating\" -> \"Cooling\" [label=\"Warm\"];
+    \"Heating\" -> \"Off\" [label=\"Stop\", ltail=\"cluster_Running\"];
+}
+");
+    }
+
+    #[test]
+    fn test_to_mermaid() {
+        assert_eq!(turnstile().to_mermaid(), ~"stateDiagram-v2
+    [*] --> Locked
+    Locked --> Unlocked : Coin
+    Unlocked --> Locked : Push [door closed]
+    Unlocked --> Broken : Kick [guard]
+    Broken --> [*]
+");
+    }
+
+    #[test]
+    fn test_to_plantuml() {
+        assert_eq!(turnstile().to_plantuml(), ~"@startuml
+[*] --> Locked
+Locked --> Unlocked : Coin
+Unlocked --> Locked : Push [door closed]
+Unlocked --> Broken : Kick [guard]
+Broken --> [*]
+@enduml
+");
+    }
+
+    #[test]
+    fn test_nested_to_mermaid() {
+        assert_eq!(heater().to_mermaid(), ~"stateDiagram-v2
+    state Running {
+        state \"Heating\" as Heating
+        state \"Cooling\" as Cooling
+        [*] --> Heating
+        Heating --> Cooling : Warm
+    }
+    [*] --> Off
+    Off --> Running : Start
+    Running --> Off : Stop
+");
+    }
+
+    #[test]
+    fn test_parallel_to_mermaid() {
+        assert_eq!(player().to_mermaid(), ~"stateDiagram-v2
+    state Player {
+        state Playback {
+            state \"Playing\" as Playing
+            state \"Paused\" as Paused
+            [*] --> Playing
+            Playing --> Paused : Pause
+        }
+        --
+        state Volume {
+            state \"Normal\" as Normal
+            state \"Muted\" as Muted
+            [*] --> Normal
+            Normal --> Muted : Mute
+        }
+    }
+    [*] --> Off
+    Off --> Player : Power
+    Player --> Off : Power
+");
+    }
+}
@@ -0,0 +1,110 @@
+//! A log of the transitions a machine has taken, kept either in full or as
+//! a ring buffer holding only the most recent records.
+
+/// One transition taken by a machine.
+#[deriving(Eq, Clone, Encodable, Decodable)]
+pub struct Record<S, E> {
+    /// Position of the transition among all the ones recorded, from zero
+    sequence: u64,
+    /// When the transition was taken, in milliseconds, according to the
+    /// machine's clock
+    timestamp: u64,
+    from: S,
+    event: E,
+    to: S
+}
+
+/// The transitions taken by a machine, oldest first.
+#[deriving(Eq, Clone, Encodable, Decodable)]
+pub struct History<S, E> {
+    /// How many records are kept, or `None` to keep them all
+    limit: Option<uint>,
+    /// The records; once `limit` is reached this is a ring buffer whose
+    /// oldest record is at `start`
+    records: ~[Record<S, E>],
+    start: uint,
+    /// The sequence number of the next record
+    sequence: u64
+}
+
+impl<S: Clone, E: Clone> History<S, E> {
+
+    /// A history that keeps every record.
+    pub fn unbounded() -> History<S, E> {
+        History { limit: None, records: ~[], start: 0, sequence: 0 }
+    }
+
+    /// A history that keeps the `limit` most recent records only. A limit
+    /// of zero keeps none, though records are still numbered.
+    pub fn bounded(limit: uint) -> History<S, E> {
+        History { limit: Some(limit), records: ~[], start: 0, sequence: 0 }
+    }
+
+    /// Append a record for a transition taken at `timestamp`, dropping the
+    /// oldest one if the history is full.
+    pub fn push(&mut self, from: S, event: E, to: S, timestamp: u64) {
+        let record = Record {
+            sequence: self.sequence,
+            timestamp: timestamp,
+            from: from,
+            event: event,
+            to: to
+        };
+        self.sequence += 1;
+
+        match self.limit {
+            Some(0) => (),
+            Some(limit) if self.records.len() == limit => {
+                self.records[self.start] = record;
+                self.start = (self.start + 1) % limit;
+            }
+            _ => self.records.push(record)
+        }
+    }
+
+    /// The number of records kept.
+    pub fn len(&self) -> uint {
+        self.records.len()
+    }
+
+    /// The records kept, oldest first.
+    pub fn records<'b>(&'b self) -> ~[&'b Record<S, E>] {
+        self.records.slice_from(self.start).iter()
+            .chain(self.records.slice_to(self.start).iter())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::History;
+
+    #[test]
+    fn test_unbounded() {
+        let mut history = History::unbounded();
+        for i in range(0, 5) {
+            history.push(i, (), i + 1, 0);
+        }
+        let records = history.records();
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].from, 0);
+        assert_eq!(records[4].sequence, 4);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut history = History::bounded(3);
+        for i in range(0, 5) {
+            history.push(i, (), i + 1, 0);
+        }
+        let sequences: ~[u64] = history.records().iter().map(|r| r.sequence).collect();
+        assert_eq!(sequences, ~[2, 3, 4]);
+        assert_eq!(history.len(), 3);
+
+        let mut history = History::bounded(0);
+        history.push(0, (), 1, 0);
+        assert_eq!(history.len(), 0);
+        history.push(1, (), 2, 0);
+        assert!(history.records().is_empty());
+    }
+}
@@ -9,22 +9,36 @@
 //! extern mod fsm;
 //!
 //! fn main() {
-//!     // Create a new set of states.
+//!     // Create a new set of states and a set of events.
 //!     // `State` defines a new module where each state is defined in.
 //!     defstates! (State -> Unlocked, Locked);
+//!     defstates! (Event -> Coin, Push);
 //!
-//!     // Create a new FSM and pass an initial state:
-//!     let mut machine = fsm::StateMachine::new(State::Unlocked);
+//!     // Create a new FSM and pass an initial state and context:
+//!     let mut machine = fsm::StateMachine::new(State::Locked, ());
+//!
+//!     // Declare which transitions are legal:
+//!     machine.add_transition(State::Locked, Event::Coin, State::Unlocked);
+//!     machine.add_transition(State::Unlocked, Event::Push, State::Locked);
 //!
 //!     // Do something when we lock the machine:
-//!     machine.when(State::Locked, || {
+//!     machine.when(State::Locked, |_| {
 //!         println!("We have locked it again.");
 //!     });
 //!
-//!     machine.switch(State::Locked);
+//!     machine.fire(Event::Coin);
+//!     machine.fire(Event::Push);
 //! }
 //! ```
 
+extern mod extra;
+
+use extra::future::Future;
+
+use clock::Clock;
+use history::{History, Record};
+use timeout::Timer;
+
 /// Create a new module that will contain an enum for each State and
 /// also implement the `Eq` trait for simple comparisons.
 ///
@@ -37,6 +51,7 @@
 macro_rules! defstates(
     ($namespace:ident -> $($name:ident),+) => (
         mod $namespace {
+            #[deriving(Clone, ToStr)]
             pub enum State {
                 $(
                     $name,
@@ -52,114 +67,1834 @@ macro_rules! defstates(
     );
 )
 
+/// Define a complete machine in one block: its states, its events, the
+/// initial state and the transition table. This creates a module holding a
+/// `State` and an `Event` enum (deriving `Eq`, `Clone` and `ToStr`), a
+/// `Machine` type, a `definition` function returning the `Definition` and a
+/// `new` function that builds a `StateMachine` running it. `StateMachine` and
+/// `Definition` have to be in scope where the macro is used.
+///
+/// A `typed` submodule offers the same machine as typestate: see
+/// `typed::Machine`, where firing an undeclared transition fails to compile.
+///
+/// The definition is checked when it is compiled. Rust has no way for a
+/// macro to report an error of its own, so each mistake shows up as whatever
+/// errors the compiler reports for the generated code:
+///
+/// * a state or event declared twice is a duplicate definition of that enum
+///   variant and of its `typed` struct;
+/// * a transition naming an undeclared state or event is an unresolved name
+///   everywhere the generated code uses it: in `definition`, in the `typed`
+///   impls and in `transition_names_an_undeclared_state_or_event`;
+/// * mapping the same state and event twice is an unreachable pattern in
+///   `duplicate_transition_for_the_same_state_and_event`, along with
+///   conflicting `typed` impls;
+/// * leaving out the initial state is a "macro undefined" error for
+///   `state_machine_requires_an_initial_state!`.
+///
+/// States and events are declared in the same module, so their names must
+/// be distinct: a state and an event both called `Open` are reported as
+/// declared twice.
+///
+/// ```rust
+/// use fsm::{StateMachine, Definition};
+///
+/// state_machine! (Turnstile {
+///     states: Locked, Unlocked;
+///     events: Coin, Push;
+///     initial: Locked;
+///     Locked + Coin => Unlocked,
+///     Unlocked + Push => Locked
+/// })
+///
+/// let mut machine = Turnstile::new(());
+/// machine.fire(Turnstile::Coin);
+/// ```
+macro_rules! state_machine(
+    ($namespace:ident {
+        states: $($state:ident),+;
+        events: $($event:ident),+;
+        initial: $initial:ident;
+        $($from:ident + $on:ident => $to:ident),+
+    }) => (
+        mod $namespace {
+            use super::StateMachine;
+            use super::Definition;
+
+            #[deriving(Eq, Clone, ToStr)]
+            pub enum State {
+                $(
+                    $state,
+                )+
+            }
+
+            #[deriving(Eq, Clone, ToStr)]
+            pub enum Event {
+                $(
+                    $event,
+                )+
+            }
+
+            /// A `StateMachine` over this definition's states and events.
+            pub type Machine<'a, C> = StateMachine<'a, State, Event, C>;
+
+            /// The state every new machine starts in.
+            pub static INITIAL: State = $initial;
+
+            /// Every declared state, in declaration order.
+            pub fn states() -> ~[State] {
+                ~[$($state),+]
+            }
+
+            /// Every declared event, in declaration order.
+            pub fn events() -> ~[Event] {
+                ~[$($event),+]
+            }
+
+            /// The definition of this machine, with every state, event and
+            /// transition declared.
+            pub fn definition<'a, C>() -> Definition<'a, State, Event, C> {
+                let mut definition = Definition::new(INITIAL);
+                $(
+                    definition.add_state($state);
+                )+
+                $(
+                    definition.add_event($event);
+                )+
+                $(
+                    definition.add_transition($from, $on, $to);
+                )+
+                definition
+            }
+
+            /// Create a machine in the initial state, holding `context`.
+            pub fn new<'a, C>(context: C) -> Machine<'a, C> {
+                StateMachine::from_definition(definition(), context)
+            }
+
+            fn build<'a, C>(state: State, context: C) -> Machine<'a, C> {
+                StateMachine::resume(definition(), state, context)
+            }
+
+            /// The same definition checked by the type system: every state
+            /// and event is a zero-sized type and every transition an impl of
+            /// `from::<state>::Event` for its event, so an illegal transition
+            /// does not compile.
+            pub mod typed {
+                $(
+                    pub struct $state;
+                )+
+
+                $(
+                    pub struct $event;
+                )+
+
+                /// One trait per state, implemented by the events leaving it.
+                /// Impls for the same type that differ only in their trait's
+                /// type parameters conflict, so `Fire` is implemented once per
+                /// state and dispatches on the event through these traits.
+                pub mod from {
+                    $(
+                        pub mod $state {
+                            /// An event leading from this state to `To`.
+                            pub trait Event<To> {
+                                fn target(self) -> To;
+                            }
+                        }
+                    )+
+                }
+
+                /// Implemented by the state types to recover the runtime state.
+                pub trait TypedState {
+                    fn state(&self) -> super::State;
+                }
+
+                $(
+                    impl TypedState for $state {
+                        fn state(&self) -> super::State {
+                            super::$state
+                        }
+                    }
+                )+
+
+                /// Firing `Ev` consumes a machine in `Self`'s state and returns
+                /// the machine `To` in the target state.
+                pub trait Fire<Ev, To> {
+                    fn fire(self, event: Ev) -> To;
+                }
+
+                /// A machine whose current state `S` is known at compile time.
+                pub struct Machine<S, C> {
+                    state: S,
+                    context: C
+                }
+
+                impl<C> Machine<$initial, C> {
+                    /// Create a machine in the initial state, holding `context`.
+                    pub fn new(context: C) -> Machine<$initial, C> {
+                        Machine { state: $initial, context: context }
+                    }
+                }
+
+                impl<S: TypedState, C> Machine<S, C> {
+                    /// The runtime value of the current state.
+                    pub fn state(&self) -> super::State {
+                        self.state.state()
+                    }
+
+                    /// Borrow the context carried by the machine.
+                    pub fn context<'b>(&'b self) -> &'b C {
+                        &self.context
+                    }
+
+                    /// Mutably borrow the context carried by the machine.
+                    pub fn context_mut<'b>(&'b mut self) -> &'b mut C {
+                        &mut self.context
+                    }
+
+                    /// Continue with a dynamic `StateMachine` in the same state.
+                    pub fn into_dynamic<'a>(self) -> super::Machine<'a, C> {
+                        super::build(self.state.state(), self.context)
+                    }
+                }
+
+                $(
+                    impl<C, To, Ev: from::$state::Event<To>> Fire<Ev, Machine<To, C>>
+                            for Machine<$state, C> {
+                        fn fire(self, event: Ev) -> Machine<To, C> {
+                            Machine { state: event.target(), context: self.context }
+                        }
+                    }
+                )+
+
+                $(
+                    impl from::$from::Event<$to> for $on {
+                        fn target(self) -> $to {
+                            $to
+                        }
+                    }
+                )+
+            }
+
+            /// Never called; fails to compile when a transition names a state
+            /// or an event that was not declared.
+            #[allow(dead_code)]
+            fn transition_names_an_undeclared_state_or_event() {
+                $(
+                    let _: State = $from;
+                    let _: Event = $on;
+                    let _: State = $to;
+                )+
+            }
+
+            /// Never called; fails to compile with an unreachable pattern when
+            /// the same state and event are mapped more than once.
+            #[allow(dead_code)]
+            fn duplicate_transition_for_the_same_state_and_event(state: State,
+                                                                 event: Event) {
+                match Some((state, event)) {
+                    $(
+                        Some(($from, $on)) => (),
+                    )+
+                    _ => ()
+                }
+            }
+        }
+    );
+    ($namespace:ident {
+        states: $($state:ident),+;
+        events: $($event:ident),+;
+        $($from:ident + $on:ident => $to:ident),+
+    }) => (
+        state_machine_requires_an_initial_state!($namespace)
+    );
+)
+
+mod analysis;
+pub mod async;
+pub mod clock;
+mod diagram;
+pub mod history;
+pub mod loader;
+pub mod runner;
+pub mod shared;
+pub mod snapshot;
+mod timeout;
+
+/// A single entry in the transition table: while the machine is in the
+/// `from` state, receiving `event` moves it to the `to` state.
+#[deriving(Clone)]
+pub struct Transition<S, E> {
+    from: S,
+    event: E,
+    to: S
+}
+
+/// An entry in the transition table: a `Transition`, the optional guards
+/// that have to allow it before the machine may move and the optional
+/// actions run while the machine moves.
+struct Rule<'a, S, E, C> {
+    transition: Transition<S, E>,
+    guard: Option<'a |&C| -> bool>,
+    /// A guard whose verdict arrives later, see the `async` module
+    asyncGuard: Option<'a |&C| -> Future<bool>>,
+    /// How the guard is shown in diagrams
+    guardName: Option<~str>,
+    action: Option<'a |&Transition<S, E>, &mut C|>,
+    /// An action that completes later, awaited before the machine moves
+    asyncAction: Option<'a |&Transition<S, E>, &C| -> Future<()>>
+}
+
+impl<'a, S, E, C> Rule<'a, S, E, C> {
+    /// A rule for the transition from `from` to `to` on `event`, with no
+    /// guard and no action.
+    fn new(from: S, event: E, to: S) -> Rule<'a, S, E, C> {
+        Rule {
+            transition: Transition { from: from, event: event, to: to },
+            guard: None,
+            asyncGuard: None,
+            guardName: None,
+            action: None,
+            asyncAction: None
+        }
+    }
+
+    /// Evaluate the guards, if any. Unguarded rules always allow the move.
+    /// The verdict of the async guard is taken from `verdict` when it has
+    /// already been waited for, and waited for here otherwise.
+    fn allows(&self, context: &C, verdict: Option<bool>) -> bool {
+        let allowed = match self.guard {
+            Some(ref guard) => (*guard)(context),
+            None => true
+        };
+        allowed && match (verdict, &self.asyncGuard) {
+            (Some(verdict), _) => verdict,
+            (None, &Some(ref guard)) => (*guard)(context).unwrap(),
+            (None, &None) => true
+        }
+    }
+
+    /// Start the async action, if any, and wait for it to complete.
+    fn prepare(&self, context: &C) {
+        match self.asyncAction {
+            Some(ref action) => (*action)(&self.transition, context).unwrap(),
+            None => ()
+        }
+    }
+
+    /// Run the action, if any, passing it the transition being taken.
+    fn act(&self, context: &mut C) {
+        match self.action {
+            Some(ref action) => (*action)(&self.transition, context),
+            None => ()
+        }
+    }
+}
+
+/// How much of the last active configuration of a composite state its
+/// history pseudo-state restores.
+#[deriving(Eq, Clone)]
+pub enum HistoryKind {
+    /// Resume the substate that was directly active, entering it afresh.
+    Shallow,
+    /// Resume every state that was active below the composite state.
+    Deep
+}
+
+/// The static description of a machine: the states and events it knows
+/// about, how states nest, the state it starts in, the states it is expected
+/// to end in and its table of transitions. A `StateMachine` runs a
+/// definition.
+pub struct Definition<'a, S, E, C> {
+    /// The state every machine running this definition starts in
+    initialState: S,
+    /// Every known state, in the order it was first declared or used
+    states: ~[S],
+    /// Every known event, in the order it was first declared or used
+    events: ~[E],
+    /// States a machine is expected to end in
+    finals: ~[S],
+    /// Pairs of a substate and the composite state directly containing it
+    parents: ~[(S, S)],
+    /// Pairs of a composite state and the substate it starts in
+    initials: ~[(S, S)],
+    /// Composite states whose substates are orthogonal regions, all active
+    /// at once
+    parallels: ~[S],
+    /// Pairs of a parallel state and the state it moves to once every one
+    /// of its regions has reached a final state
+    joins: ~[(S, S)],
+    /// History pseudo-states, with the composite state they belong to
+    histories: ~[(S, S, HistoryKind)],
+    /// States, how long the machine may stay in them, in milliseconds, and
+    /// the event fired once that time has passed
+    timeouts: ~[(S, u64, E)],
+    /// Every declared transition, in the order it was added
+    rules: ~[Rule<'a, S, E, C>]
+}
+
+impl<'a, S: Eq + Clone, E: Eq + Clone, C> Definition<'a, S, E, C> {
+
+    /// Creates a new, empty definition that starts in `initialState`.
+    pub fn new(initialState: S) -> Definition<'a, S, E, C> {
+        Definition {
+            initialState: initialState.clone(),
+            states: ~[initialState],
+            events: ~[],
+            finals: ~[],
+            parents: ~[],
+            initials: ~[],
+            parallels: ~[],
+            joins: ~[],
+            histories: ~[],
+            timeouts: ~[],
+            rules: ~[]
+        }
+    }
+
+    /// Declare a state. States used by transitions are declared implicitly,
+    /// so this is only needed for states no transition mentions yet.
+    pub fn add_state(&mut self, state: S) {
+        if !self.states.contains(&state) {
+            self.states.push(state);
+        }
+    }
+
+    /// Declare an event. Events used by transitions are declared implicitly,
+    /// so this is only needed for events no transition mentions yet.
+    pub fn add_event(&mut self, event: E) {
+        if !self.events.contains(&event) {
+            self.events.push(event);
+        }
+    }
+
+    /// Mark `state` as final: a state the machine is expected to end in, and
+    /// which therefore needs no outgoing transitions.
+    pub fn add_final(&mut self, state: S) {
+        self.add_state(state.clone());
+        if !self.finals.contains(&state) {
+            self.finals.push(state);
+        }
+    }
+
+    /// Make `child` a substate of the composite state `parent`. While the
+    /// machine is in `child` it is also in `parent`: events `child` has no
+    /// transition for are handled by `parent`. The first substate added to a
+    /// parent is the one entered when a transition targets the parent, unless
+    /// `set_initial_substate` picks another one.
+    pub fn add_substate(&mut self, parent: S, child: S) {
+        self.add_state(parent.clone());
+        self.add_state(child.clone());
+        if self.initial_substate(&parent).is_none() {
+            self.initials.push((parent.clone(), child.clone()));
+        }
+        self.parents.push((child, parent));
+    }
+
+    /// Enter `child` whenever a transition targets the composite `parent`.
+    pub fn set_initial_substate(&mut self, parent: S, child: S) {
+        self.initials.retain(|pair| pair.first_ref() != &parent);
+        self.initials.push((parent, child));
+    }
+
+    /// Make `region` an orthogonal region of `parent`. While the machine is
+    /// in `parent` it is in one substate of every region at once, and each
+    /// event is dispatched to all of them. Regions are usually composite
+    /// states themselves.
+    pub fn add_region(&mut self, parent: S, region: S) {
+        self.add_substate(parent.clone(), region);
+        if !self.parallels.contains(&parent) {
+            self.parallels.push(parent);
+        }
+    }
+
+    /// Move from the parallel state `state` to `to` as soon as every region
+    /// of `state` is in a final state.
+    pub fn add_join(&mut self, state: S, to: S) {
+        self.add_state(state.clone());
+        self.add_state(to.clone());
+        self.joins.push((state, to));
+    }
+
+    /// Make `pseudo` a shallow history pseudo-state of the composite state
+    /// `parent`. A transition targeting `pseudo` enters `parent` in the
+    /// substate that was directly active when `parent` was last left, or in
+    /// its initial substate if it was never left. The substates of that
+    /// substate are entered afresh.
+    pub fn add_shallow_history(&mut self, parent: S, pseudo: S) {
+        self.add_history(parent, pseudo, Shallow);
+    }
+
+    /// Make `pseudo` a deep history pseudo-state of the composite state
+    /// `parent`. A transition targeting `pseudo` enters `parent` in every
+    /// state that was active below it when it was last left, or in its
+    /// initial configuration if it was never left.
+    pub fn add_deep_history(&mut self, parent: S, pseudo: S) {
+        self.add_history(parent, pseudo, Deep);
+    }
+
+    fn add_history(&mut self, parent: S, pseudo: S, kind: HistoryKind) {
+        self.add_state(parent.clone());
+        self.add_state(pseudo.clone());
+        self.histories.push((pseudo, parent, kind));
+    }
+
+    /// Declare that receiving `event` while in the `from` state moves the
+    /// machine to the `to` state. Only declared transitions can be fired.
+    pub fn add_transition(&mut self, from: S, event: E, to: S) {
+        self.add_rule(from, event, to, None, None);
+    }
+
+    /// Declare a transition that may only be taken while `guard` returns
+    /// `true` for the context. The guard is evaluated when the event is
+    /// fired, before the current state changes. Several guarded transitions
+    /// may share the same state and event; the first one whose guard allows
+    /// it is taken.
+    pub fn add_guarded_transition(&mut self, from: S, event: E, to: S,
+                                  guard: 'a |&C| -> bool) {
+        self.add_rule(from, event, to, Some(guard), None);
+    }
+
+    /// Declare a guarded transition whose guard is shown as `name` when the
+    /// definition is rendered as a diagram.
+    pub fn add_named_guarded_transition(&mut self, from: S, event: E, to: S,
+                                        name: &str, guard: 'a |&C| -> bool) {
+        let mut rule = Rule::new(from, event, to);
+        rule.guard = Some(guard);
+        rule.guardName = Some(name.to_owned());
+        self.push_rule(rule);
+    }
+
+    /// Declare a transition that runs `action` every time it is taken. The
+    /// action receives the transition and the context, after the exit hooks
+    /// of the source state and before the entry hooks of the target state.
+    pub fn add_transition_action(&mut self, from: S, event: E, to: S,
+                                 action: 'a |&Transition<S, E>, &mut C|) {
+        self.add_rule(from, event, to, None, Some(action));
+    }
+
+    /// Declare a transition with both an optional guard and an optional
+    /// action. The other `add_*` methods are shorthands for this one.
+    pub fn add_rule(&mut self, from: S, event: E, to: S,
+                    guard: Option<'a |&C| -> bool>,
+                    action: Option<'a |&Transition<S, E>, &mut C|>) {
+        let mut rule = Rule::new(from, event, to);
+        rule.guard = guard;
+        rule.action = action;
+        self.push_rule(rule);
+    }
+
+    /// Add `rule` to the table, declaring its states and event.
+    fn push_rule(&mut self, rule: Rule<'a, S, E, C>) {
+        self.add_state(rule.transition.from.clone());
+        self.add_state(rule.transition.to.clone());
+        self.add_event(rule.transition.event.clone());
+        self.rules.push(rule);
+    }
+
+    /// The state every machine running this definition starts in.
+    pub fn initial<'b>(&'b self) -> &'b S {
+        &self.initialState
+    }
+
+    /// Every known state, in the order it was first declared or used.
+    pub fn states<'b>(&'b self) -> &'b [S] {
+        self.states.as_slice()
+    }
+
+    /// Every known event, in the order it was first declared or used.
+    pub fn events<'b>(&'b self) -> &'b [E] {
+        self.events.as_slice()
+    }
+
+    /// Whether `state` has been marked as final.
+    pub fn is_final(&self, state: &S) -> bool {
+        self.finals.contains(state)
+    }
+
+    /// Every declared transition, in the order it was added.
+    pub fn transitions<'b>(&'b self) -> ~[&'b Transition<S, E>] {
+        self.rules.iter().map(|rule| &rule.transition).collect()
+    }
+
+    /// The composite state directly containing `state`, if any.
+    pub fn parent<'b>(&'b self, state: &S) -> Option<&'b S> {
+        for pair in self.parents.iter() {
+            match *pair {
+                (ref child, ref parent) if *child == *state => return Some(parent),
+                _ => ()
+            }
+        }
+        None
+    }
+
+    /// The substate entered when a transition targets `state`, if `state` is
+    /// composite.
+    pub fn initial_substate<'b>(&'b self, state: &S) -> Option<&'b S> {
+        for pair in self.initials.iter() {
+            match *pair {
+                (ref parent, ref child) if *parent == *state => return Some(child),
+                _ => ()
+            }
+        }
+        None
+    }
+
+    /// Whether `state` contains substates.
+    pub fn is_composite(&self, state: &S) -> bool {
+        self.initial_substate(state).is_some()
+    }
+
+    /// The composite state `state` is a history pseudo-state of, and how
+    /// much it restores, if `state` is a history pseudo-state.
+    pub fn history_of<'b>(&'b self, state: &S) -> Option<(&'b S, HistoryKind)> {
+        for history in self.histories.iter() {
+            match *history {
+                (ref pseudo, ref parent, kind) if *pseudo == *state => {
+                    return Some((parent, kind));
+                }
+                _ => ()
+            }
+        }
+        None
+    }
+
+    /// Whether the substates of `state` are orthogonal regions.
+    pub fn is_parallel(&self, state: &S) -> bool {
+        self.parallels.contains(state)
+    }
+
+    /// The substates directly contained in `state`, in the order they were
+    /// added.
+    pub fn substates(&self, state: &S) -> ~[S] {
+        self.parents.iter()
+            .filter(|pair| pair.second_ref() == state)
+            .map(|pair| pair.first_ref().clone())
+            .collect()
+    }
+
+    /// `state` followed by every composite state containing it, innermost
+    /// first.
+    pub fn lineage(&self, state: &S) -> ~[S] {
+        let mut lineage = ~[state.clone()];
+        loop {
+            let next = match self.parent(&lineage[lineage.len() - 1]) {
+                Some(parent) => parent.clone(),
+                None => return lineage
+            };
+            lineage.push(next);
+        }
+    }
+
+    /// The leaf states active once `state` has been entered from the top
+    /// level: the initial leaf of every region along the way.
+    pub fn initial_configuration(&self, state: &S) -> ~[S] {
+        self.entry([state.clone()], &None).move_iter()
+            .filter(|s| !self.is_composite(s))
+            .collect()
+    }
+
+    /// The leaf state actually entered when a transition targets `state`:
+    /// `state` itself, or the initial substate of every composite state on
+    /// the way down. For parallel states this follows the first region only.
+    pub fn initial_leaf(&self, state: S) -> S {
+        let mut leaf = state;
+        loop {
+            leaf = match self.initial_substate(&leaf) {
+                Some(child) => child.clone(),
+                None => return leaf
+            };
+        }
+    }
+
+    /// The innermost composite state that strictly contains both `from` and
+    /// `to`, or `None` if only the top level does. A transition between them
+    /// exits and enters every state below it.
+    fn domain(&self, from: &S, to: &S) -> Option<S> {
+        let outer = self.lineage(to);
+        for state in self.lineage(from).move_iter().skip(1) {
+            if outer.iter().skip(1).any(|s| *s == state) {
+                return Some(state);
+            }
+        }
+        None
+    }
+
+    /// Every state entered, outermost first, by a transition targeting
+    /// `targets` whose domain is `domain`: the states on the way down to each
+    /// target, the other regions of any parallel state on the way, and the
+    /// initial substates below the targets. Every target has to lie below
+    /// the same child of `domain`.
+    fn entry(&self, targets: &[S], domain: &Option<S>) -> ~[S] {
+        let mut path = self.lineage_below(&targets[0], domain);
+        let mut entered = ~[];
+        self.enter(path.pop(), targets, &mut entered);
+        entered
+    }
+
+    /// Add `state` to `entered`, followed by the substates entered with it:
+    /// every region if `state` is parallel, the substate leading to one of
+    /// the `targets` if there is one, and the initial substate otherwise.
+    fn enter(&self, state: S, targets: &[S], entered: &mut ~[S]) {
+        let children = if self.is_parallel(&state) {
+            self.substates(&state)
+        } else {
+            match targets.iter().filter_map(|t| self.child_towards(&state, t)).next() {
+                Some(child) => ~[child],
+                None => match self.initial_substate(&state) {
+                    Some(child) => ~[child.clone()],
+                    None => ~[]
+                }
+            }
+        };
+        entered.push(state);
+        for child in children.move_iter() {
+            self.enter(child, targets, entered);
+        }
+    }
+
+    /// The substate of `state` that contains `target`, or is `target`, if
+    /// `target` lies strictly below `state`.
+    fn child_towards(&self, state: &S, target: &S) -> Option<S> {
+        let lineage = self.lineage(target);
+        match lineage.iter().position(|s| *s == *state) {
+            Some(index) if index > 0 => Some(lineage[index - 1].clone()),
+            _ => None
+        }
+    }
+
+    /// `state` and the composite states containing it, innermost first, up
+    /// to but excluding `domain`.
+    fn lineage_below(&self, state: &S, domain: &Option<S>) -> ~[S] {
+        self.lineage(state).move_iter()
+            .take_while(|s| match *domain {
+                Some(ref domain) => *s != *domain,
+                None => true
+            })
+            .collect()
+    }
+}
+
+/// The reason a transition was refused.
+#[deriving(Eq, Clone)]
+pub enum Reason {
+    /// No transition was declared for the current state and the event.
+    NoTransition,
+    /// A transition was declared, but its guard did not allow it.
+    GuardRejected,
+    /// The machine has terminated and no longer accepts events.
+    Terminated
+}
+
+impl Reason {
+    /// A short, human readable description of the reason.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            NoTransition => "no such transition",
+            GuardRejected => "guard rejected",
+            Terminated => "machine terminated"
+        }
+    }
+}
+
+/// Returned by `.fire` when a transition is refused. The machine is left in
+/// the state it was in before the event was fired.
+pub struct TransitionError<S, E> {
+    /// The state the machine was (and still is) in, as given by `.state()`.
+    state: S,
+    /// The event that was fired.
+    event: E,
+    /// The state the transition would have moved to, if one was declared.
+    target: Option<S>,
+    /// Why the transition was refused.
+    reason: Reason
+}
+
+impl<S, E> ToStr for TransitionError<S, E> {
+    fn to_str(&self) -> ~str {
+        let target = match self.target {
+            Some(ref target) => format!(" to {:?}", target),
+            None => ~""
+        };
+        format!("cannot fire {:?} in {:?}{}: {}",
+                self.event, self.state, target, self.reason.description())
+    }
+}
+
+/// Returned by `StateMachine::replay` when one of the events is refused.
+pub struct ReplayError<S, E> {
+    /// The position of the refused event in the replayed sequence.
+    index: uint,
+    /// Why it was refused.
+    error: TransitionError<S, E>
+}
+
+/// Run every hook registered for `state`, in the order they were added.
+fn trigger<'a, S: Eq, C>(hooks: &~[(S, 'a |&mut C|)], state: &S,
+                         context: &mut C) {
+    for hook in hooks.iter() {
+        match *hook {
+            (ref s, ref func) => {
+                if *s == *state {
+                    (*func)(context);
+                }
+            }
+        }
+    }
+}
+
 /// A representation of a state machine that holds the current state,
-/// as well as an owned vector of tuple elements. The tuple contains the
-/// state and a lambda, specified with a named lifetime.
-pub struct StateMachine<'a, T> {
-    /// Store the currently selected state
-    currentState: T,
-    exprs: ~[(T, 'a ||)]
+/// the definition it runs, as well as owned vectors of tuple elements. Each
+/// tuple contains a state and a lambda, specified with a named lifetime.
+pub struct StateMachine<'a, S, E, C> {
+    /// The active leaf states: a single one, or one for every active
+    /// orthogonal region, in the order the regions were declared
+    configuration: ~[S],
+    /// The states, events and transitions this machine runs
+    definition: Definition<'a, S, E, C>,
+    /// Hooks run after a state has been entered
+    exprs: ~[(S, 'a |&mut C|)],
+    /// Hooks run before a state is left
+    exits: ~[(S, 'a |&mut C|)],
+    /// Actions run for every transition taken
+    actions: ~['a |&Transition<S, E>, &mut C|],
+    /// Callbacks run once the machine reaches a final state
+    completions: ~['a |&S, &mut C|],
+    /// User data carried alongside the current state
+    context: C,
+    /// The transitions taken so far, if they are being recorded
+    history: Option<History<S, E>>,
+    /// Pairs of a composite state and the leaves that were active below it
+    /// when it was last left, restored by history pseudo-states
+    remembered: ~[(S, ~[S])],
+    /// Where the time used by timers, history and dwell times comes from
+    clock: &'a Clock,
+    /// Pairs of an active state and when it was entered
+    enteredAt: ~[(S, u64)],
+    /// Pending timeouts and delayed events
+    timers: ~[Timer<S, E>]
 }
 
-/// Establish two generic types parameters: `'a` which defines the lifetime
-/// of the closure/lambda to `.when` methods; and `T` which defines the type
-/// of state object.
-impl<'a, T: Eq> StateMachine<'a, T> {
+/// Establish four generic types parameters: `'a` which defines the lifetime
+/// of the closure/lambda to `.when` methods; `S` which defines the type
+/// of state object; `E` which defines the type of event object; and `C`
+/// which defines the type of the context shared by guards, actions and hooks.
+impl<'a, S: Eq + Clone, E: Eq + Clone, C> StateMachine<'a, S, E, C> {
 
     /// Creates a new instance of the `StateMachine` struct. We begin
-    /// with an empty set of expressions and an initial state.
-    pub fn new(initialState: T) -> StateMachine<T> {
-        StateMachine { currentState: initialState, exprs: ~[] }
-    }
-
-    /// Transition/switch the current state to another one. This will trigger
-    /// any `.when` expressions that match.
-    pub fn switch(&mut self, nextState: T) {
-        self.currentState = nextState;
-        for expr in self.exprs.iter() {
-            match *expr {
-                (ref state, ref func) => {
-                    if *state == self.currentState {
-                        (*func)();
+    /// with an empty definition, an empty set of expressions, an
+    /// initial state and the initial context.
+    pub fn new(initialState: S, context: C) -> StateMachine<'a, S, E, C> {
+        StateMachine::from_definition(Definition::new(initialState), context)
+    }
+
+    /// Creates a machine running `definition`, starting in its initial state.
+    pub fn from_definition(definition: Definition<'a, S, E, C>,
+                           context: C) -> StateMachine<'a, S, E, C> {
+        let initialState = definition.initial().clone();
+        StateMachine::resume(definition, initialState, context)
+    }
+
+    /// Creates a machine running `definition` that is already in `state`,
+    /// or in its initial configuration if `state` is composite. No hooks are
+    /// run for entering it, but its timeouts start.
+    pub fn resume(definition: Definition<'a, S, E, C>, state: S,
+                  context: C) -> StateMachine<'a, S, E, C> {
+        let mut machine = StateMachine {
+            configuration: definition.initial_configuration(&state),
+            definition: definition,
+            exprs: ~[],
+            exits: ~[],
+            actions: ~[],
+            completions: ~[],
+            context: context,
+            history: None,
+            remembered: ~[],
+            clock: &clock::SYSTEM as &'a Clock,
+            enteredAt: ~[],
+            timers: ~[]
+        };
+        machine.restart_clock();
+        machine
+    }
+
+    /// Rebuild a machine by replaying `events`, in order, from the initial
+    /// state of `definition`. Guards are evaluated as usual; transition actions
+    /// are only run when `runActions` is set, so replaying a log does not have
+    /// to repeat its side effects. Replaying stops at the first refused event.
+    pub fn replay(definition: Definition<'a, S, E, C>, context: C, events: ~[E],
+                  runActions: bool) -> Result<StateMachine<'a, S, E, C>,
+                                              ReplayError<S, E>> {
+        let mut machine = StateMachine::from_definition(definition, context);
+        for (index, event) in events.move_iter().enumerate() {
+            match machine.step(event, runActions, []) {
+                Ok(_) => (),
+                Err(error) => return Err(ReplayError { index: index, error: error })
+            }
+        }
+        Ok(machine)
+    }
+
+    /// Borrow the definition this machine runs.
+    pub fn definition<'b>(&'b self) -> &'b Definition<'a, S, E, C> {
+        &self.definition
+    }
+
+    /// Declare a transition. See `Definition::add_transition`.
+    pub fn add_transition(&mut self, from: S, event: E, to: S) {
+        self.definition.add_transition(from, event, to);
+    }
+
+    /// Declare a guarded transition. See `Definition::add_guarded_transition`.
+    pub fn add_guarded_transition(&mut self, from: S, event: E, to: S,
+                                  guard: 'a |&C| -> bool) {
+        self.definition.add_guarded_transition(from, event, to, guard);
+    }
+
+    /// Declare a guarded transition with a named guard. See
+    /// `Definition::add_named_guarded_transition`.
+    pub fn add_named_guarded_transition(&mut self, from: S, event: E, to: S,
+                                        name: &str, guard: 'a |&C| -> bool) {
+        self.definition.add_named_guarded_transition(from, event, to, name,
+                                                     guard);
+    }
+
+    /// Declare a transition with an action. See
+    /// `Definition::add_transition_action`.
+    pub fn add_transition_action(&mut self, from: S, event: E, to: S,
+                                 action: 'a |&Transition<S, E>, &mut C|) {
+        self.definition.add_transition_action(from, event, to, action);
+    }
+
+    /// Declare a transition with an optional guard and an optional action.
+    /// See `Definition::add_rule`.
+    pub fn add_rule(&mut self, from: S, event: E, to: S,
+                    guard: Option<'a |&C| -> bool>,
+                    action: Option<'a |&Transition<S, E>, &mut C|>) {
+        self.definition.add_rule(from, event, to, guard, action);
+    }
+
+    /// Register an action that runs for every transition the machine takes,
+    /// right after the action of the transition itself. This is the place for
+    /// logic that has to see every move, such as auditing or metrics.
+    pub fn on_transition(&mut self, action: 'a |&Transition<S, E>, &mut C|) {
+        self.actions.push(action);
+    }
+
+    /// Register a callback that runs once the machine has reached a final
+    /// state and finished, receiving that state and the context. It runs
+    /// after the entry hooks of the final state.
+    pub fn on_complete(&mut self, func: 'a |&S, &mut C|) {
+        self.completions.push(func);
+    }
+
+    /// Fire an event against the current state. The target state is looked
+    /// up in the transition table, first for the current state and then for
+    /// each composite state containing it, innermost first. When one is found
+    /// the exit hooks of every state being left run, innermost first,
+    /// followed by the transition actions. The machine then moves to the
+    /// target, descending into initial substates, and runs the entry hooks
+    /// (including `.when` expressions) of every state being entered,
+    /// outermost first, returning the new state. A `TransitionError` is
+    /// returned, and the current state is left untouched, if the transition
+    /// is refused. Every event is refused once the machine has finished.
+    ///
+    /// Inside a parallel state the event is dispatched to every region in
+    /// turn, and it is only refused if no region accepts it. A region left
+    /// by a transition taken for an earlier one does not see the event. Once
+    /// every region of a parallel state with a join is final, the join is
+    /// taken as well. The state returned is the one the last transition
+    /// taken moved to.
+    pub fn fire(&mut self, event: E) -> Result<S, TransitionError<S, E>> {
+        self.step(event, true, [])
+    }
+
+    /// The state the machine is currently in, always a leaf of the hierarchy.
+    /// Inside a parallel state this is the active leaf of the first region.
+    pub fn state<'b>(&'b self) -> &'b S {
+        &self.configuration[0]
+    }
+
+    /// Every active leaf state, one per active region, in the order the
+    /// regions were declared.
+    pub fn configuration<'b>(&'b self) -> &'b [S] {
+        self.configuration.as_slice()
+    }
+
+    /// Whether the machine has reached a final state at the top level of the
+    /// hierarchy, after which it accepts no more events. Final substates
+    /// only complete their region or composite state.
+    pub fn is_finished(&self) -> bool {
+        self.configuration.iter().all(|leaf| {
+            self.definition.is_final(leaf) && self.definition.parent(leaf).is_none()
+        })
+    }
+
+    /// Whether the machine is in `state`, either directly or in one of its
+    /// substates.
+    pub fn is_in(&self, state: &S) -> bool {
+        self.configuration.iter().any(|leaf| {
+            self.definition.lineage(leaf).contains(state)
+        })
+    }
+
+    /// Every state the machine is in: the active leaves and every composite
+    /// state containing them.
+    fn active_states(&self) -> ~[S] {
+        let mut active = ~[];
+        for leaf in self.configuration.iter() {
+            for state in self.definition.lineage(leaf).move_iter() {
+                if !active.contains(&state) {
+                    active.push(state);
+                }
+            }
+        }
+        active
+    }
+
+    /// Treat every active state as entered at the current time of the
+    /// clock, restarting their timeouts.
+    fn restart_clock(&mut self) {
+        let now = self.clock.now();
+        self.enteredAt = ~[];
+        self.timers = ~[];
+        for state in self.active_states().move_iter() {
+            self.arm(&state);
+            self.enteredAt.push((state, now));
+        }
+    }
+
+    /// Start the timers for the timeouts declared for `state`.
+    fn arm(&mut self, state: &S) {
+        let now = self.clock.now();
+        for timeout in self.definition.timeouts.iter() {
+            match *timeout {
+                (ref s, after, ref event) if *s == *state => {
+                    self.timers.push(Timer {
+                        state: s.clone(),
+                        due: now + after,
+                        event: event.clone(),
+                        ready: false
+                    });
+                }
+                _ => ()
+            }
+        }
+    }
+
+    /// Take the transitions for `event`, running the transition actions
+    /// only if `runActions` is set. `verdicts` holds the index and verdict
+    /// of the async guards that have already been waited for.
+    fn step(&mut self, event: E, runActions: bool,
+            verdicts: &[(uint, bool)]) -> Result<S, TransitionError<S, E>> {
+        if self.is_finished() {
+            return Err(self.refuse(event, None, Terminated));
+        }
+
+        let mut pending = self.configuration.clone();
+        let mut reached = None;
+        let mut rejected = None;
+        while !pending.is_empty() {
+            let leaf = pending.shift();
+            match self.select(&leaf, &event, verdicts) {
+                Ok(index) => {
+                    let transition = self.definition.rules[index].transition.clone();
+                    let (exited, next) = self.transfer(&leaf, transition,
+                                                       Some(index), runActions);
+                    pending.retain(|s| !exited.contains(s));
+                    reached = Some(next);
+                }
+                Err(target) => {
+                    if rejected.is_none() {
+                        rejected = target;
+                    }
+                }
+            }
+        }
+
+        let next = match reached {
+            Some(next) => self.join(&event, runActions).unwrap_or(next),
+            None => {
+                let reason = if rejected.is_none() { NoTransition } else { GuardRejected };
+                return Err(self.refuse(event, rejected, reason));
+            }
+        };
+        if self.is_finished() {
+            for func in self.completions.iter() {
+                (*func)(&next, &mut self.context);
+            }
+        }
+        Ok(next)
+    }
+
+    /// Take `transition` out of the active `leaf`, along with the rule at
+    /// `index` if it comes from one. Every active state below the domain of
+    /// the transition that contains `leaf`'s outermost exited state is left,
+    /// innermost first. Returns the leaves that were left and the first leaf
+    /// entered.
+    fn transfer(&mut self, leaf: &S, transition: Transition<S, E>,
+                index: Option<uint>, runActions: bool) -> (~[S], S) {
+        let (target, targets) = self.resolve(&transition.to);
+        let domain = self.definition.domain(&transition.from, &target);
+        let mut lineage = self.definition.lineage_below(leaf, &domain);
+        let outermost = lineage.pop();
+        let mut exited = ~[];
+        let mut exitedLeaves = ~[];
+        for active in self.configuration.iter() {
+            let lineage = self.definition.lineage_below(active, &domain);
+            if !lineage.contains(&outermost) {
+                continue;
+            }
+            exitedLeaves.push(active.clone());
+            for state in lineage.move_rev_iter() {
+                if !exited.contains(&state) {
+                    exited.push(state);
+                }
+            }
+        }
+        exited.reverse();
+        let entered = self.definition.entry(targets.as_slice(), &domain);
+        let leaves: ~[S] = entered.iter()
+            .filter(|s| !self.definition.is_composite(*s))
+            .map(|s| s.clone())
+            .collect();
+
+        if runActions {
+            match index {
+                Some(index) => self.definition.rules[index].prepare(&self.context),
+                None => ()
+            }
+        }
+
+        for state in exited.iter() {
+            if !self.definition.is_composite(state) {
+                continue;
+            }
+            let below: ~[S] = self.configuration.iter()
+                .filter(|active| self.definition.lineage(*active).contains(state))
+                .map(|active| active.clone())
+                .collect();
+            self.remembered.retain(|pair| pair.first_ref() != state);
+            self.remembered.push((state.clone(), below));
+        }
+
+        self.timers.retain(|timer| !exited.contains(&timer.state));
+        self.enteredAt.retain(|pair| !exited.contains(pair.first_ref()));
+        for state in exited.iter() {
+            trigger(&self.exits, state, &mut self.context);
+        }
+        if runActions {
+            match index {
+                Some(index) => self.definition.rules[index].act(&mut self.context),
+                None => ()
+            }
+            for action in self.actions.iter() {
+                (*action)(&transition, &mut self.context);
+            }
+        }
+        match self.history {
+            Some(ref mut log) => {
+                log.push(leaf.clone(), transition.event.clone(),
+                         leaves[0].clone(), self.clock.now());
+            }
+            None => ()
+        }
+
+        let mut configuration = ~[];
+        for active in self.configuration.iter() {
+            if !exitedLeaves.contains(active) {
+                configuration.push(active.clone());
+            } else if *active == *exitedLeaves.head() {
+                configuration.push_all(leaves.as_slice());
+            }
+        }
+        self.configuration = configuration;
+        let now = self.clock.now();
+        for state in entered.iter() {
+            self.enteredAt.push((state.clone(), now));
+            self.arm(state);
+        }
+        for state in entered.iter() {
+            trigger(&self.exprs, state, &mut self.context);
+        }
+
+        (exitedLeaves, leaves[0].clone())
+    }
+
+    /// The state a transition targeting `to` actually enters, along with the
+    /// states to enter below it: `to` itself, or the composite state owning
+    /// the history pseudo-state `to` and what it remembers.
+    fn resolve(&self, to: &S) -> (S, ~[S]) {
+        let (parent, kind) = match self.definition.history_of(to) {
+            Some((parent, kind)) => (parent.clone(), kind),
+            None => return (to.clone(), ~[to.clone()])
+        };
+        let remembered = self.remembered.iter().find(|pair| *pair.first_ref() == parent);
+        let leaves = match remembered {
+            Some(pair) => pair.second_ref().clone(),
+            None => return (parent.clone(), ~[parent])
+        };
+
+        let targets = match kind {
+            Deep => leaves,
+            Shallow => {
+                let mut children = ~[];
+                for leaf in leaves.iter() {
+                    let child = self.definition.child_towards(&parent, leaf).unwrap();
+                    if !children.contains(&child) {
+                        children.push(child);
                     }
                 }
+                children
             }
+        };
+        (parent, targets)
+    }
+
+    /// Take the join of every parallel state whose regions are all final,
+    /// as if the `event` that completed them had been declared for it.
+    /// Returns the first leaf entered by the last join taken, if any.
+    fn join(&mut self, event: &E, runActions: bool) -> Option<S> {
+        let mut reached = None;
+        loop {
+            let join = self.definition.joins.iter()
+                .find(|pair| self.is_complete(pair.first_ref()))
+                .map(|pair| pair.clone());
+            let (state, to) = match join {
+                Some(join) => join,
+                None => return reached
+            };
+            let leaf = self.configuration.iter()
+                .find(|leaf| self.definition.lineage(*leaf).contains(&state))
+                .unwrap().clone();
+            let transition = Transition { from: state, event: event.clone(), to: to };
+            let (_, next) = self.transfer(&leaf, transition, None, runActions);
+            reached = Some(next);
         }
     }
 
+    /// Whether the machine is in the parallel `state` with every one of its
+    /// regions in a final state.
+    fn is_complete(&self, state: &S) -> bool {
+        self.is_in(state) && self.definition.substates(state).iter().all(|region| {
+            self.configuration.iter().any(|leaf| {
+                self.definition.is_final(leaf) &&
+                    self.definition.lineage(leaf).contains(region)
+            })
+        })
+    }
+
     /// Pass a lambda/closure whenever a specific state is triggered. This is
     /// typically how and where the logic goes. `'a` defines a named lifetime
     /// based on the lambda, because lambda's capture their environment.
-    pub fn when(&mut self, state: T, func: 'a ||) {
+    pub fn when(&mut self, state: S, func: 'a |&mut C|) {
+        self.on_enter(state, func);
+    }
+
+    /// Register a hook that runs every time `state` is entered, after the
+    /// current state has been updated.
+    pub fn on_enter(&mut self, state: S, func: 'a |&mut C|) {
         self.exprs.push((state, func));
     }
+
+    /// Register a hook that runs every time `state` is left, before the
+    /// current state is updated.
+    pub fn on_exit(&mut self, state: S, func: 'a |&mut C|) {
+        self.exits.push((state, func));
+    }
+
+    /// Borrow the context carried by the machine.
+    pub fn context<'b>(&'b self) -> &'b C {
+        &self.context
+    }
+
+    /// Mutably borrow the context carried by the machine.
+    pub fn context_mut<'b>(&'b mut self) -> &'b mut C {
+        &mut self.context
+    }
+
+    /// Read the time from `clock` from now on. The states the machine is in
+    /// count as entered at the current time of `clock`: their timeouts start
+    /// again and delayed events are dropped.
+    pub fn set_clock(&mut self, clock: &'a Clock) {
+        self.clock = clock;
+        self.restart_clock();
+    }
+
+    /// How long, in milliseconds, the machine has been in `state`, or `None`
+    /// if it is not in it. This is 0 if the clock has gone back to before the
+    /// state was entered.
+    pub fn time_in(&self, state: &S) -> Option<u64> {
+        let now = self.clock.now();
+        self.enteredAt.iter()
+            .find(|pair| pair.first_ref() == state)
+            .map(|pair| {
+                let entered = *pair.second_ref();
+                if now > entered { now - entered } else { 0 }
+            })
+    }
+
+    /// Start recording every transition taken into `history`, replacing the
+    /// history recorded so far, if any.
+    pub fn record_history(&mut self, history: History<S, E>) {
+        self.history = Some(history);
+    }
+
+    /// The transitions recorded so far, oldest first. This is empty unless
+    /// `record_history` has been called.
+    pub fn history<'b>(&'b self) -> ~[&'b Record<S, E>] {
+        match self.history {
+            Some(ref history) => history.records(),
+            None => ~[]
+        }
+    }
+
+    /// Build the error reported when `event` is refused in the current state.
+    fn refuse(&self, event: E, target: Option<S>,
+              reason: Reason) -> TransitionError<S, E> {
+        TransitionError {
+            state: self.state().clone(),
+            event: event,
+            target: target,
+            reason: reason
+        }
+    }
+
+    /// Pick the index of the first rule declared for `event` whose guard
+    /// allows it, looking at the active `leaf` and then at each composite
+    /// state containing it. When there is no such rule the error holds the
+    /// target of the first rejected candidate, or `None` if nothing was
+    /// declared.
+    fn select(&self, leaf: &S, event: &E,
+              verdicts: &[(uint, bool)]) -> Result<uint, Option<S>> {
+        let mut rejected = None;
+        for state in self.definition.lineage(leaf).iter() {
+            for (index, rule) in self.definition.rules.iter().enumerate() {
+                if rule.transition.from != *state ||
+                   rule.transition.event != *event {
+                    continue;
+                }
+                let verdict = verdicts.iter()
+                    .find(|pair| *pair.first_ref() == index)
+                    .map(|pair| *pair.second_ref());
+                if rule.allows(&self.context, verdict) {
+                    return Ok(index);
+                }
+                if rejected.is_none() {
+                    rejected = Some(rule.transition.to.clone());
+                }
+            }
+        }
+        Err(rejected)
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::cell::Cell;
+    use StateMachine;
+    use Definition;
+    use clock::{Clock, ManualClock};
+
+    state_machine! (Turnstile {
+        states: Locked, Unlocked;
+        events: Coin, Push;
+        initial: Locked;
+        Locked + Coin => Unlocked,
+        Unlocked + Push => Locked
+    })
 
+    state_machine! (Door {
+        states: Closed, Opened, Locked;
+        events: Open, Close, Lock, Unlock;
+        initial: Closed;
+        Closed + Open => Opened,
+        Closed + Lock => Locked,
+        Opened + Close => Closed,
+        Locked + Unlock => Closed
+    })
 
     #[test]
     fn test_sm_new() {
         defstates! (State -> One);
-        let sm = ::StateMachine::new(State::One);
-        assert_eq!(sm.currentState, State::One);
+        defstates! (Event -> Go);
+        let sm: ::StateMachine<State::State, Event::State, ()> =
+            ::StateMachine::new(State::One, ());
+        assert_eq!(*sm.state(), State::One);
+    }
+
+    #[test]
+    fn test_sm_fire() {
+        defstates! (State -> Unlocked, Locked);
+        defstates! (Event -> Coin, Push);
+
+        let mut sm = ::StateMachine::new(State::Locked, ());
+        sm.add_transition(State::Locked, Event::Coin, State::Unlocked);
+        sm.add_transition(State::Unlocked, Event::Push, State::Locked);
+
+        assert_eq!(sm.fire(Event::Coin).ok(), Some(State::Unlocked));
+        assert_eq!(*sm.state(), State::Unlocked);
+        assert_eq!(sm.fire(Event::Push).ok(), Some(State::Locked));
+        assert_eq!(*sm.state(), State::Locked);
     }
 
-    // FIXME: Replace with the `defstates!` macro.
     #[test]
-    fn test_sm_switch() {
+    fn test_sm_fire_undeclared() {
+        defstates! (State -> Unlocked, Locked);
+        defstates! (Event -> Coin, Push);
 
-        enum State {
-            Unlocked = 0x01,
-            Locked
+        let mut sm = ::StateMachine::new(State::Locked, ());
+        sm.add_transition(State::Locked, Event::Coin, State::Unlocked);
+
+        match sm.fire(Event::Push) {
+            Ok(_) => fail!("an undeclared transition was accepted"),
+            Err(err) => {
+                assert_eq!(err.state, State::Locked);
+                assert_eq!(err.event, Event::Push);
+                assert!(err.target.is_none());
+                assert_eq!(err.reason, ::NoTransition);
+            }
         }
+        assert_eq!(*sm.state(), State::Locked);
+    }
 
-        impl Eq for State {
-            fn eq(&self, rs: &State) -> bool {
-                *self as int == *rs as int
+    #[test]
+    fn test_guard_rejected() {
+        defstates! (State -> Unlocked, Locked);
+        defstates! (Event -> Coin, Push);
+
+        let mut sm = ::StateMachine::new(State::Locked, 1);
+        sm.add_guarded_transition(State::Locked, Event::Coin, State::Unlocked,
+                                  |coins| *coins >= 2);
+
+        match sm.fire(Event::Coin) {
+            Ok(_) => fail!("a rejected guard allowed the transition"),
+            Err(err) => {
+                assert_eq!(err.target, Some(State::Unlocked));
+                assert_eq!(err.reason, ::GuardRejected);
             }
         }
+        assert_eq!(*sm.state(), State::Locked);
+    }
 
-        let mut sm = ::StateMachine::new(Unlocked);
-        sm.switch(Locked);
-        assert_eq!(sm.currentState as int, Locked as int);
+    #[test]
+    fn test_guard_falls_through() {
+        defstates! (State -> Unlocked, Locked, Broken);
+        defstates! (Event -> Coin, Push);
+
+        let mut sm = ::StateMachine::new(State::Locked, ());
+        sm.add_guarded_transition(State::Locked, Event::Coin, State::Broken,
+                                  |_| false);
+        sm.add_guarded_transition(State::Locked, Event::Coin, State::Unlocked,
+                                  |_| true);
+
+        assert_eq!(sm.fire(Event::Coin).ok(), Some(State::Unlocked));
     }
 
-    // FIXME: Replace with the `defstates!` macro.
     #[test]
-    fn test_when() {
+    fn test_transition_actions() {
+        defstates! (State -> Unlocked, Locked);
+        defstates! (Event -> Coin, Push);
+
+        let moves = Cell::new(0);
+        let mut unlocked = false;
+
+        let mut sm = ::StateMachine::new(State::Locked, ());
+        sm.add_transition_action(State::Locked, Event::Coin, State::Unlocked,
+                                 |t, _| {
+            unlocked = t.from == State::Locked && t.to == State::Unlocked &&
+                       t.event == Event::Coin;
+        });
+        sm.add_transition(State::Unlocked, Event::Push, State::Locked);
+        sm.on_transition(|_, _| moves.set(moves.get() + 1));
+
+        assert!(sm.fire(Event::Coin).is_ok());
+        assert!(sm.fire(Event::Push).is_ok());
+        assert!(unlocked);
+        assert_eq!(moves.get(), 2);
+    }
+
+    #[test]
+    fn test_context() {
+        defstates! (State -> Unlocked, Locked);
+        defstates! (Event -> Coin, Push);
+
+        let mut sm = ::StateMachine::new(State::Locked, 0);
+        sm.add_rule(State::Locked, Event::Coin, State::Locked,
+                    Some(|coins: &int| *coins < 1),
+                    Some(|_, coins: &mut int| *coins += 1));
+        sm.add_guarded_transition(State::Locked, Event::Coin, State::Unlocked,
+                                  |coins| *coins >= 1);
+        sm.on_exit(State::Unlocked, |coins| *coins = 0);
+        sm.add_transition(State::Unlocked, Event::Push, State::Locked);
+
+        assert_eq!(sm.fire(Event::Coin).ok(), Some(State::Locked));
+        assert_eq!(*sm.context(), 1);
+        assert_eq!(sm.fire(Event::Coin).ok(), Some(State::Unlocked));
+        assert!(sm.fire(Event::Push).is_ok());
+        assert_eq!(*sm.context(), 0);
+
+        *sm.context_mut() = 5;
+        assert_eq!(sm.fire(Event::Coin).ok(), Some(State::Unlocked));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut sm = Turnstile::new(());
+        assert!(sm.fire(Turnstile::Coin).is_ok());
+        assert!(sm.history().is_empty());
+
+        sm.record_history(::history::History::bounded(2));
+        assert!(sm.fire(Turnstile::Push).is_ok());
+        assert!(sm.fire(Turnstile::Push).is_err());
+        assert!(sm.fire(Turnstile::Coin).is_ok());
+        assert!(sm.fire(Turnstile::Push).is_ok());
 
-        enum State {
-            Unlocked = 0x01,
-            Locked
+        let history = sm.history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].sequence, 1);
+        assert_eq!(history[0].from, Turnstile::Locked);
+        assert_eq!(history[0].event, Turnstile::Coin);
+        assert_eq!(history[1].to, Turnstile::Locked);
+        assert!(history[0].timestamp <= history[1].timestamp);
+    }
+
+    #[test]
+    fn test_clock() {
+        let clock = ManualClock::new(1000);
+        let mut sm = Turnstile::new(());
+        sm.set_clock(&clock as &Clock);
+        sm.record_history(::history::History::unbounded());
+
+        clock.advance(250);
+        assert_eq!(sm.time_in(&Turnstile::Locked), Some(250));
+        assert!(sm.fire(Turnstile::Coin).is_ok());
+        assert_eq!(sm.history()[0].timestamp, 1250);
+        assert!(sm.time_in(&Turnstile::Locked).is_none());
+
+        clock.advance(40);
+        assert_eq!(sm.time_in(&Turnstile::Unlocked), Some(40));
+
+        clock.set(0);
+        assert_eq!(sm.time_in(&Turnstile::Unlocked), Some(0));
+    }
+
+    #[test]
+    fn test_replay() {
+        let events = ~[Turnstile::Coin, Turnstile::Push, Turnstile::Coin];
+        let sm = StateMachine::replay(Turnstile::definition(), (), events, true);
+        assert_eq!(*sm.ok().unwrap().state(), Turnstile::Unlocked);
+
+        let events = ~[Turnstile::Coin, Turnstile::Push, Turnstile::Push];
+        match StateMachine::replay(Turnstile::definition(), (), events, true) {
+            Ok(_) => fail!("an invalid event log was replayed"),
+            Err(err) => {
+                assert_eq!(err.index, 2);
+                assert_eq!(err.error.state, Turnstile::Locked);
+                assert_eq!(err.error.reason, ::NoTransition);
+            }
         }
+    }
+
+    #[test]
+    fn test_replay_without_actions() {
+        let mut def = Definition::new(Turnstile::Locked);
+        def.add_rule(Turnstile::Locked, Turnstile::Coin, Turnstile::Unlocked,
+                     Some(|paid: &int| *paid >= 0),
+                     Some(|_, paid: &mut int| *paid += 1));
+
+        let sm = StateMachine::replay(def, 0, ~[Turnstile::Coin], false);
+        let sm = sm.ok().unwrap();
+        assert_eq!(*sm.state(), Turnstile::Unlocked);
+        assert_eq!(*sm.context(), 0);
+    }
+
+    #[test]
+    fn test_nested_states() {
+        defstates! (State -> Off, Running, Heating, Cooling);
+        defstates! (Event -> Start, Warm, Cool, Stop);
+
+        let mut def = Definition::new(State::Off);
+        def.add_substate(State::Running, State::Heating);
+        def.add_substate(State::Running, State::Cooling);
+        def.add_transition(State::Off, Event::Start, State::Running);
+        def.add_transition(State::Heating, Event::Warm, State::Cooling);
+        def.add_transition(State::Cooling, Event::Cool, State::Heating);
+        def.add_transition(State::Running, Event::Stop, State::Off);
+
+        let mut sm = StateMachine::from_definition(def, ());
+        assert_eq!(sm.fire(Event::Start).ok(), Some(State::Heating));
+        assert!(sm.is_in(&State::Running));
+        assert_eq!(sm.fire(Event::Warm).ok(), Some(State::Cooling));
+
+        // Cooling has no transition for Stop, so Running handles it.
+        assert_eq!(sm.fire(Event::Stop).ok(), Some(State::Off));
+        assert!(!sm.is_in(&State::Running));
+    }
+
+    #[test]
+    fn test_nested_hook_order() {
+        defstates! (State -> Off, Running, Heating, Cooling);
+        defstates! (Event -> Start, Stop);
+
+        let step = Cell::new(0);
+        let enterRunning = Cell::new(0);
+        let enterHeating = Cell::new(0);
+        let exitHeating = Cell::new(0);
+        let exitRunning = Cell::new(0);
+
+        let mut def = Definition::new(State::Off);
+        def.add_substate(State::Running, State::Heating);
+        def.add_substate(State::Running, State::Cooling);
+        def.add_transition(State::Off, Event::Start, State::Running);
+        def.add_transition(State::Running, Event::Stop, State::Off);
+
+        let mut sm = StateMachine::from_definition(def, ());
+        sm.on_enter(State::Running, |_| {
+            step.set(step.get() + 1);
+            enterRunning.set(step.get());
+        });
+        sm.on_enter(State::Heating, |_| {
+            step.set(step.get() + 1);
+            enterHeating.set(step.get());
+        });
+        sm.on_exit(State::Running, |_| {
+            step.set(step.get() + 1);
+            exitRunning.set(step.get());
+        });
+        sm.on_exit(State::Heating, |_| {
+            step.set(step.get() + 1);
+            exitHeating.set(step.get());
+        });
+
+        assert!(sm.fire(Event::Start).is_ok());
+        assert!(sm.fire(Event::Stop).is_ok());
+        assert_eq!(enterRunning.get(), 1);
+        assert_eq!(enterHeating.get(), 2);
+        assert_eq!(exitHeating.get(), 3);
+        assert_eq!(exitRunning.get(), 4);
+    }
+
+    #[test]
+    fn test_parallel_regions() {
+        defstates! (State -> Off, Player, Playback, Playing, Paused, Volume,
+                    Normal, Muted);
+        defstates! (Event -> Power, Pause, Mute);
+
+        let exits = Cell::new(0);
+
+        let mut def = Definition::new(State::Off);
+        def.add_region(State::Player, State::Playback);
+        def.add_region(State::Player, State::Volume);
+        def.add_substate(State::Playback, State::Playing);
+        def.add_substate(State::Playback, State::Paused);
+        def.add_substate(State::Volume, State::Normal);
+        def.add_substate(State::Volume, State::Muted);
+        def.add_transition(State::Off, Event::Power, State::Player);
+        def.add_transition(State::Playing, Event::Pause, State::Paused);
+        def.add_transition(State::Normal, Event::Mute, State::Muted);
+        def.add_transition(State::Player, Event::Power, State::Off);
+
+        let mut sm = StateMachine::from_definition(def, ());
+        sm.on_exit(State::Player, |_| exits.set(exits.get() + 1));
+
+        assert!(sm.fire(Event::Power).is_ok());
+        assert_eq!(sm.configuration().to_owned(), ~[State::Playing, State::Normal]);
+        assert_eq!(sm.fire(Event::Mute).ok(), Some(State::Muted));
+        assert_eq!(sm.fire(Event::Pause).ok(), Some(State::Paused));
+        assert_eq!(sm.configuration().to_owned(), ~[State::Paused, State::Muted]);
+        assert!(sm.is_in(&State::Volume));
+
+        // Both regions see Power, but the first one leaves Player for both.
+        assert_eq!(sm.fire(Event::Power).ok(), Some(State::Off));
+        assert_eq!(sm.configuration().to_owned(), ~[State::Off]);
+        assert_eq!(exits.get(), 1);
+    }
+
+    #[test]
+    fn test_join() {
+        defstates! (State -> Job, Download, Fetching, Fetched, Build, Compiling,
+                    Compiled, Done);
+        defstates! (Event -> Fetch, Compile);
+
+        let mut def = Definition::new(State::Job);
+        def.add_region(State::Job, State::Download);
+        def.add_region(State::Job, State::Build);
+        def.add_substate(State::Download, State::Fetching);
+        def.add_substate(State::Download, State::Fetched);
+        def.add_substate(State::Build, State::Compiling);
+        def.add_substate(State::Build, State::Compiled);
+        def.add_final(State::Fetched);
+        def.add_final(State::Compiled);
+        def.add_transition(State::Fetching, Event::Fetch, State::Fetched);
+        def.add_transition(State::Compiling, Event::Compile, State::Compiled);
+        def.add_join(State::Job, State::Done);
+
+        let mut sm = StateMachine::from_definition(def, ());
+        assert_eq!(sm.configuration().to_owned(),
+                   ~[State::Fetching, State::Compiling]);
+        assert_eq!(sm.fire(Event::Compile).ok(), Some(State::Compiled));
+        assert_eq!(sm.fire(Event::Fetch).ok(), Some(State::Done));
+        assert!(!sm.is_in(&State::Job));
+    }
+
+    #[test]
+    fn test_history_states() {
+        defstates! (State -> Off, Running, Heating, Cooling, Slow, Fast,
+                    RunningShallow, RunningDeep);
+        defstates! (Event -> Start, Resume, ResumeDeep, Warm, Faster, Stop);
+
+        let mut def = Definition::new(State::Off);
+        def.add_substate(State::Running, State::Heating);
+        def.add_substate(State::Running, State::Cooling);
+        def.add_substate(State::Cooling, State::Slow);
+        def.add_substate(State::Cooling, State::Fast);
+        def.add_shallow_history(State::Running, State::RunningShallow);
+        def.add_deep_history(State::Running, State::RunningDeep);
+        def.add_transition(State::Off, Event::Start, State::Running);
+        def.add_transition(State::Off, Event::Resume, State::RunningShallow);
+        def.add_transition(State::Off, Event::ResumeDeep, State::RunningDeep);
+        def.add_transition(State::Heating, Event::Warm, State::Cooling);
+        def.add_transition(State::Slow, Event::Faster, State::Fast);
+        def.add_transition(State::Running, Event::Stop, State::Off);
+
+        let mut sm = StateMachine::from_definition(def, ());
+
+        // Nothing is remembered yet, so history enters the initial substate.
+        assert_eq!(sm.fire(Event::Resume).ok(), Some(State::Heating));
+        assert!(sm.fire(Event::Warm).is_ok());
+        assert!(sm.fire(Event::Faster).is_ok());
+        assert!(sm.fire(Event::Stop).is_ok());
+
+        assert_eq!(sm.fire(Event::ResumeDeep).ok(), Some(State::Fast));
+        assert!(sm.fire(Event::Stop).is_ok());
+        assert_eq!(sm.fire(Event::Resume).ok(), Some(State::Slow));
+        assert!(sm.fire(Event::Stop).is_ok());
+        assert_eq!(sm.fire(Event::Start).ok(), Some(State::Heating));
+    }
+
+    #[test]
+    fn test_completion() {
+        defstates! (State -> Pending, Approved, Rejected);
+        defstates! (Event -> Approve, Reject);
+
+        let mut completed = None;
+
+        let mut def = Definition::new(State::Pending);
+        def.add_final(State::Approved);
+        def.add_final(State::Rejected);
+        def.add_transition(State::Pending, Event::Approve, State::Approved);
+        def.add_transition(State::Pending, Event::Reject, State::Rejected);
+        def.add_transition(State::Approved, Event::Reject, State::Rejected);
+
+        {
+            let mut sm = StateMachine::from_definition(def, ());
+            sm.on_complete(|state, _| completed = Some(*state));
+            assert!(!sm.is_finished());
+            assert!(sm.fire(Event::Approve).is_ok());
+            assert!(sm.is_finished());
 
-        /// FIXME: This model is **super** akward to present as an API.
-        ///        Perhaps move away from enums and into custom types?
-        impl Eq for State {
-            fn eq(&self, rs: &State) -> bool {
-                *self as int == *rs as int
+            match sm.fire(Event::Reject) {
+                Ok(_) => fail!("a finished machine accepted an event"),
+                Err(err) => assert_eq!(err.reason, ::Terminated)
             }
+            assert_eq!(*sm.state(), State::Approved);
         }
+        assert_eq!(completed, Some(State::Approved));
+    }
+
+    #[test]
+    fn test_when() {
+        defstates! (State -> Unlocked, Locked);
+        defstates! (Event -> Coin, Push);
 
-        let mut sm = ::StateMachine::new(Unlocked);
+        let mut sm = ::StateMachine::new(State::Unlocked, ());
+        sm.add_transition(State::Unlocked, Event::Push, State::Locked);
         let mut called = false;
 
-        sm.when(Locked, || {
+        sm.when(State::Locked, |_| {
             called = true;
             println!("Hello from Locked!");
         });
 
         assert_eq!(called, false);
-        sm.switch(Locked);
+        assert!(sm.fire(Event::Push).is_ok());
         assert_eq!(called, true);
     }
 
+    #[test]
+    fn test_enter_exit_order() {
+        defstates! (State -> Unlocked, Locked);
+        defstates! (Event -> Coin, Push);
+
+        let step = Cell::new(0);
+        let exited = Cell::new(0);
+        let entered = Cell::new(0);
+
+        let mut sm = ::StateMachine::new(State::Locked, ());
+        sm.add_transition(State::Locked, Event::Coin, State::Unlocked);
+        sm.on_enter(State::Unlocked, |_| {
+            step.set(step.get() + 1);
+            entered.set(step.get());
+        });
+        sm.on_exit(State::Locked, |_| {
+            step.set(step.get() + 1);
+            exited.set(step.get());
+        });
+
+        assert!(sm.fire(Event::Coin).is_ok());
+        assert_eq!(exited.get(), 1);
+        assert_eq!(entered.get(), 2);
+    }
+
+    #[test]
+    fn test_state_machine_macro() {
+        let mut sm = Turnstile::new(());
+        assert_eq!(*sm.state(), Turnstile::Locked);
+        assert_eq!(sm.fire(Turnstile::Coin).ok(), Some(Turnstile::Unlocked));
+        assert!(sm.fire(Turnstile::Coin).is_err());
+        assert_eq!(sm.fire(Turnstile::Push).ok(), Some(Turnstile::Locked));
+
+        assert_eq!(Turnstile::states(), ~[Turnstile::Locked, Turnstile::Unlocked]);
+        assert_eq!(Turnstile::events(), ~[Turnstile::Coin, Turnstile::Push]);
+        assert_eq!(Turnstile::Unlocked.to_str(), ~"Unlocked");
+    }
+
+    #[test]
+    fn test_typestate() {
+        use test::Turnstile::typed::{Machine, Fire, Coin, Push};
+
+        let sm = Machine::new(0);
+        assert_eq!(sm.state(), Turnstile::Locked);
+
+        let mut sm = sm.fire(Coin);
+        assert_eq!(sm.state(), Turnstile::Unlocked);
+        *sm.context_mut() += 1;
+
+        let sm = sm.fire(Push).fire(Coin);
+        assert_eq!(*sm.context(), 1);
+
+        let mut dynamic = sm.into_dynamic();
+        assert_eq!(*dynamic.state(), Turnstile::Unlocked);
+        assert_eq!(dynamic.fire(Turnstile::Push).ok(), Some(Turnstile::Locked));
+    }
+
+    #[test]
+    fn test_typestate_branches() {
+        use test::Door::typed::{Machine, Fire, Open, Close, Lock, Unlock};
+
+        let sm = Machine::new(()).fire(Open).fire(Close);
+        assert_eq!(sm.state(), Door::Closed);
+
+        let sm = sm.fire(Lock);
+        assert_eq!(sm.state(), Door::Locked);
+        assert_eq!(sm.fire(Unlock).state(), Door::Closed);
+    }
+
     #[test]
     fn test_defstates_macro() {
         defstates! (State -> Woot, Wolf);
         assert_eq!(State::Woot as int, 0);
         assert_eq!(State::Wolf as int, 1);
     }
-}
\ No newline at end of file
+}
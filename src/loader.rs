@@ -0,0 +1,281 @@
+//! Build machines from a JSON document instead of compiled-in code, for
+//! workflows that are configured rather than programmed. States and events
+//! are identified by name:
+//!
+//! ```json
+//! {
+//!     "states": ["Pending", "Approved", "Rejected"],
+//!     "events": ["approve", "reject"],
+//!     "initial": "Pending",
+//!     "final": ["Approved", "Rejected"],
+//!     "transitions": [
+//!         {"from": "Pending", "event": "approve", "to": "Approved"},
+//!         {"from": "Pending", "event": "reject", "to": "Rejected"}
+//!     ]
+//! }
+//! ```
+//!
+//! `events` and `final` are optional. When `events` is given, even as an
+//! empty list, transitions may only use the events it lists.
+
+use extra::json;
+use extra::json::Json;
+use extra::treemap::TreeMap;
+
+use Definition;
+use StateMachine;
+
+macro_rules! try_load(
+    ($e:expr) => (
+        match $e {
+            Ok(value) => value,
+            Err(err) => return Err(err)
+        }
+    );
+)
+
+/// A state known only at runtime, identified by its name.
+#[deriving(Eq, Clone, Encodable, Decodable)]
+pub struct DynState {
+    name: ~str
+}
+
+impl DynState {
+    /// The state called `name`.
+    pub fn new(name: &str) -> DynState {
+        DynState { name: name.to_owned() }
+    }
+}
+
+impl ToStr for DynState {
+    fn to_str(&self) -> ~str {
+        self.name.clone()
+    }
+}
+
+/// An event known only at runtime, identified by its name.
+#[deriving(Eq, Clone, Encodable, Decodable)]
+pub struct DynEvent {
+    name: ~str
+}
+
+impl DynEvent {
+    /// The event called `name`.
+    pub fn new(name: &str) -> DynEvent {
+        DynEvent { name: name.to_owned() }
+    }
+}
+
+impl ToStr for DynEvent {
+    fn to_str(&self) -> ~str {
+        self.name.clone()
+    }
+}
+
+/// Why a document could not be loaded. `path` points at the offending entry,
+/// such as `transitions[2].to`, and is empty when the document as a whole is
+/// at fault.
+#[deriving(Eq, Clone)]
+pub struct LoadError {
+    path: ~str,
+    message: ~str
+}
+
+impl LoadError {
+    fn new(path: &str, message: ~str) -> LoadError {
+        LoadError { path: path.to_owned(), message: message }
+    }
+}
+
+impl ToStr for LoadError {
+    fn to_str(&self) -> ~str {
+        if self.path.is_empty() {
+            self.message.clone()
+        } else {
+            format!("{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Parse `source` into a definition over named states and events.
+pub fn load_definition<'a, C>(source: &str)
+        -> Result<Definition<'a, DynState, DynEvent, C>, LoadError> {
+    let document = match json::from_str(source) {
+        Ok(document) => document,
+        Err(err) => return Err(LoadError::new("", err.to_str()))
+    };
+    let root = match document {
+        json::Object(ref root) => &**root,
+        _ => return Err(LoadError::new("", ~"expected an object"))
+    };
+
+    let states = try_load!(names(root, "states", true));
+    for (index, name) in states.iter().enumerate() {
+        if states.slice_to(index).contains(name) {
+            return Err(LoadError::new(format!("states[{}]", index),
+                                      format!("duplicate state \"{}\"", *name)));
+        }
+    }
+
+    let events = try_load!(names(root, "events", false));
+    let checkEvents = root.contains_key(&~"events");
+    let initial = try_load!(declared(&states, try_load!(string(root, "initial")),
+                                     "initial"));
+
+    let mut definition = Definition::new(DynState::new(initial));
+    for name in states.iter() {
+        definition.add_state(DynState::new(*name));
+    }
+    for name in events.iter() {
+        definition.add_event(DynEvent::new(*name));
+    }
+
+    let finals = try_load!(names(root, "final", false));
+    for (index, name) in finals.iter().enumerate() {
+        let path = format!("final[{}]", index);
+        definition.add_final(DynState::new(try_load!(declared(&states, *name, path))));
+    }
+
+    let transitions = match root.find(&~"transitions") {
+        Some(&json::List(ref transitions)) => transitions,
+        Some(_) => return Err(LoadError::new("transitions", ~"expected a list")),
+        None => return Err(LoadError::new("transitions", ~"missing"))
+    };
+    let mut seen: ~[(&str, &str)] = ~[];
+    for (index, transition) in transitions.iter().enumerate() {
+        let path = format!("transitions[{}]", index);
+        let fields = match *transition {
+            json::Object(ref fields) => &**fields,
+            _ => return Err(LoadError::new(path, ~"expected an object"))
+        };
+
+        let from = try_load!(declared(&states, try_load!(field(fields, path, "from")),
+                                      format!("{}.from", path)));
+        let to = try_load!(declared(&states, try_load!(field(fields, path, "to")),
+                                    format!("{}.to", path)));
+        let event = try_load!(field(fields, path, "event"));
+        if checkEvents && !events.contains(&event) {
+            return Err(LoadError::new(format!("{}.event", path),
+                                      format!("unknown event \"{}\"", event)));
+        }
+        if seen.contains(&(from, event)) {
+            return Err(LoadError::new(path, format!(
+                "duplicate transition from \"{}\" on \"{}\"", from, event)));
+        }
+        seen.push((from, event));
+
+        definition.add_transition(DynState::new(from), DynEvent::new(event),
+                                  DynState::new(to));
+    }
+
+    Ok(definition)
+}
+
+/// Parse `source` into a machine in the document's initial state, holding
+/// `context`.
+pub fn load<'a, C>(source: &str, context: C)
+        -> Result<StateMachine<'a, DynState, DynEvent, C>, LoadError> {
+    let definition = try_load!(load_definition(source));
+    Ok(StateMachine::from_definition(definition, context))
+}
+
+/// Read `key` of `root` as a string.
+fn string<'b>(root: &'b TreeMap<~str, Json>, key: &str) -> Result<&'b str, LoadError> {
+    match root.find(&key.to_owned()) {
+        Some(&json::String(ref value)) => Ok(value.as_slice()),
+        Some(_) => Err(LoadError::new(key, ~"expected a string")),
+        None => Err(LoadError::new(key, ~"missing"))
+    }
+}
+
+/// Read `key` of the transition at `path` as a string.
+fn field<'b>(fields: &'b TreeMap<~str, Json>, path: &str,
+             key: &str) -> Result<&'b str, LoadError> {
+    match string(fields, key) {
+        Ok(value) => Ok(value),
+        Err(err) => Err(LoadError::new(format!("{}.{}", path, err.path),
+                                       err.message))
+    }
+}
+
+/// Read `key` of `root` as a list of strings. A missing key is an error if
+/// it is `required`, and an empty list otherwise.
+fn names<'b>(root: &'b TreeMap<~str, Json>, key: &str,
+             required: bool) -> Result<~[&'b str], LoadError> {
+    let list = match root.find(&key.to_owned()) {
+        Some(&json::List(ref list)) => list,
+        Some(_) => return Err(LoadError::new(key, ~"expected a list")),
+        None if required => return Err(LoadError::new(key, ~"missing")),
+        None => return Ok(~[])
+    };
+
+    let mut names = ~[];
+    for (index, item) in list.iter().enumerate() {
+        match *item {
+            json::String(ref name) => names.push(name.as_slice()),
+            _ => return Err(LoadError::new(format!("{}[{}]", key, index),
+                                           ~"expected a string"))
+        }
+    }
+    Ok(names)
+}
+
+/// Check that `name`, found at `path`, is one of the declared `states`.
+fn declared<'b>(states: &~[&str], name: &'b str,
+                path: &str) -> Result<&'b str, LoadError> {
+    if states.contains(&name) {
+        Ok(name)
+    } else {
+        Err(LoadError::new(path, format!("unknown state \"{}\"", name)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load, DynState, DynEvent, LoadError};
+
+    static APPROVAL: &'static str = "{
+        \"states\": [\"Pending\", \"Approved\", \"Rejected\"],
+        \"initial\": \"Pending\",
+        \"final\": [\"Approved\", \"Rejected\"],
+        \"transitions\": [
+            {\"from\": \"Pending\", \"event\": \"approve\", \"to\": \"Approved\"},
+            {\"from\": \"Pending\", \"event\": \"reject\", \"to\": \"Rejected\"}
+        ]
+    }";
+
+    fn error(source: &str) -> LoadError {
+        match load(source, ()) {
+            Ok(_) => fail!("an invalid document was loaded"),
+            Err(err) => err
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let mut sm = load(APPROVAL, ()).unwrap();
+        assert_eq!(*sm.state(), DynState::new("Pending"));
+        assert!(sm.fire(DynEvent::new("approve")).is_ok());
+        assert_eq!(*sm.state(), DynState::new("Approved"));
+        assert!(sm.definition().is_final(sm.state()));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let err = error(APPROVAL.replace("\"to\": \"Rejected\"", "\"to\": \"Lost\""));
+        assert_eq!(err.to_str(), ~"transitions[1].to: unknown state \"Lost\"");
+
+        let err = error(APPROVAL.replace("\"initial\": \"Pending\",", ""));
+        assert_eq!(err.to_str(), ~"initial: missing");
+
+        let err = error(APPROVAL.replace("\"reject\"", "\"approve\""));
+        assert_eq!(err.to_str(),
+                   ~"transitions[1]: duplicate transition from \"Pending\" on \"approve\"");
+
+        let err = error(APPROVAL.replace("\"initial\"", "\"events\": [], \"initial\""));
+        assert_eq!(err.to_str(), ~"transitions[0].event: unknown event \"approve\"");
+
+        let err = error("[]");
+        assert_eq!(err.to_str(), ~"expected an object");
+    }
+}
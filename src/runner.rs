@@ -0,0 +1,231 @@
+//! Run a machine in a task of its own and talk to it over a channel. Hooks,
+//! guards and actions run in the machine's task, so they are free to block
+//! on I/O without holding up whoever fires events, and any number of
+//! `Handle`s can feed the same machine.
+//!
+//! A machine borrows its closures, so it cannot be moved into a task; it is
+//! built inside the task instead, which then hands it to `serve`:
+//!
+//! ```rust
+//! let handle = runner::spawn(proc(requests) {
+//!     let mut machine = Turnstile::new(());
+//!     machine.when(Turnstile::Locked, |_| println!("locked"));
+//!     runner::serve(&mut machine, requests, |_| ());
+//! });
+//!
+//! let state = handle.fire(Turnstile::Coin);
+//! ```
+
+use std::io::timer::Timer;
+use std::task;
+use extra::future::Future;
+
+use StateMachine;
+use TransitionError;
+
+/// A message sent to a running machine.
+pub enum Request<S, E> {
+    /// Fire the event and send the outcome back.
+    Fire(E, Chan<Result<S, TransitionError<S, E>>>),
+    /// Send back the state the machine is in.
+    State(Chan<S>),
+    /// Stop serving requests.
+    Stop
+}
+
+/// Why a request sent through a `Handle` failed.
+pub enum RunError<S, E> {
+    /// The machine refused the event.
+    Rejected(TransitionError<S, E>),
+    /// The machine has stopped serving requests, or stopped before
+    /// answering this one.
+    Stopped
+}
+
+/// Sends events to a machine running in another task. Handles can be cloned
+/// and shared; events are processed one at a time, in the order the machine
+/// receives them. Once the machine has stopped, every request returns
+/// `Stopped` instead.
+#[deriving(Clone)]
+pub struct Handle<S, E> {
+    chan: SharedChan<Request<S, E>>
+}
+
+impl<S: Send, E: Send> Handle<S, E> {
+
+    /// Fire `event` without waiting for it to be processed. The returned
+    /// future resolves to the outcome of the transition.
+    pub fn send(&self, event: E) -> Future<Result<S, RunError<S, E>>> {
+        let (port, chan) = Chan::new();
+        if !self.chan.try_send(Fire(event, chan)) {
+            return Future::from_value(Err(Stopped));
+        }
+        Future::from_fn(proc() {
+            match port.recv_opt() {
+                Some(Ok(state)) => Ok(state),
+                Some(Err(error)) => Err(Rejected(error)),
+                None => Err(Stopped)
+            }
+        })
+    }
+
+    /// Fire `event` and wait for the outcome of the transition.
+    pub fn fire(&self, event: E) -> Result<S, RunError<S, E>> {
+        self.send(event).unwrap()
+    }
+
+    /// The state the machine is in once every event sent before has been
+    /// processed.
+    pub fn state(&self) -> Result<S, RunError<S, E>> {
+        let (port, chan) = Chan::new();
+        if !self.chan.try_send(State(chan)) {
+            return Err(Stopped);
+        }
+        match port.recv_opt() {
+            Some(state) => Ok(state),
+            None => Err(Stopped)
+        }
+    }
+
+    /// Ask the machine to stop once every event sent before has been
+    /// processed.
+    pub fn stop(&self) -> Result<(), RunError<S, E>> {
+        if self.chan.try_send(Stop) { Ok(()) } else { Err(Stopped) }
+    }
+}
+
+/// Spawn a task running `body`, which is expected to build a machine and
+/// `serve` the requests it is given. Returns a handle for sending them.
+pub fn spawn<S: Send, E: Send>(body: proc(Port<Request<S, E>>)) -> Handle<S, E> {
+    let (port, chan) = SharedChan::new();
+    task::spawn(proc() {
+        body(port);
+    });
+    Handle { chan: chan }
+}
+
+/// Process `requests` against `machine` until a `Stop` request arrives or
+/// every handle has been dropped. While there is no request to process, the
+/// task waits for the machine's next timer to be due (see
+/// `StateMachine::next_deadline`) and polls it then, so timeouts fire even
+/// if no event is sent. `fired` is given the outcome of every event fired by
+/// a timer.
+///
+/// Deadlines are read from the machine's clock but waited for in real time,
+/// so the timers of a machine given a `ManualClock` are only checked again
+/// when a request arrives or the real-time wait ends.
+pub fn serve<'a, S: Send + Eq + Clone, E: Send + Eq + Clone, C>(
+        machine: &mut StateMachine<'a, S, E, C>, mut requests: Port<Request<S, E>>,
+        fired: |Result<S, TransitionError<S, E>>|) {
+    let mut timer = Timer::new();
+    loop {
+        for outcome in machine.poll().move_iter() {
+            fired(outcome);
+        }
+
+        let request = match (machine.next_deadline(), timer.as_mut()) {
+            (Some(deadline), Some(timer)) => {
+                let now = machine.clock.now();
+                let wait = if deadline > now { deadline - now } else { 0 };
+                let mut due = timer.oneshot(wait);
+                select! (
+                    request = requests.recv_opt() => request,
+                    () = due.recv() => continue
+                )
+            }
+            _ => requests.recv_opt()
+        };
+        match request {
+            Some(Fire(event, reply)) => {
+                reply.try_send(machine.fire(event));
+            }
+            Some(State(reply)) => {
+                reply.try_send(machine.state().clone());
+            }
+            Some(Stop) | None => return
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::task;
+
+    use StateMachine;
+    use super::{spawn, serve, RunError, Stopped};
+
+    defstates! (State -> Locked, Unlocked)
+    defstates! (Event -> Coin, Push, Timeout)
+
+    #[test]
+    fn test_runner() {
+        let handle = spawn(proc(requests) {
+            let mut machine = StateMachine::new(State::Locked, ());
+            machine.add_transition(State::Locked, Event::Coin, State::Unlocked);
+            machine.add_transition(State::Unlocked, Event::Push, State::Locked);
+            serve(&mut machine, requests, |_| ());
+        });
+
+        assert_eq!(handle.fire(Event::Coin).ok(), Some(State::Unlocked));
+        let pushed = handle.send(Event::Push);
+        assert!(handle.fire(Event::Push).is_err());
+        assert_eq!(pushed.unwrap().ok(), Some(State::Locked));
+        assert_eq!(handle.state().ok(), Some(State::Locked));
+        assert!(handle.stop().is_ok());
+    }
+
+    fn is_stopped<T>(result: Result<T, RunError<State::State, Event::State>>) -> bool {
+        match result {
+            Err(Stopped) => true,
+            _ => false
+        }
+    }
+
+    #[test]
+    fn test_stopped_machine() {
+        let handle = spawn(proc(requests) {
+            let mut machine = StateMachine::new(State::Locked, ());
+            machine.add_transition(State::Locked, Event::Coin, State::Unlocked);
+            serve(&mut machine, requests, |_| ());
+        });
+
+        assert!(handle.stop().is_ok());
+        assert!(is_stopped(handle.fire(Event::Coin)));
+        assert!(is_stopped(handle.state()));
+        assert!(is_stopped(handle.stop()));
+    }
+
+    #[test]
+    fn test_shared_handles() {
+        let handle = spawn(proc(requests) {
+            let mut machine = StateMachine::new(State::Locked, ());
+            machine.add_transition(State::Locked, Event::Coin, State::Unlocked);
+            serve(&mut machine, requests, |_| ());
+        });
+
+        let other = handle.clone();
+        let (port, chan) = Chan::new();
+        task::spawn(proc() {
+            chan.send(other.fire(Event::Coin).is_ok());
+        });
+        assert!(port.recv());
+        assert_eq!(handle.state().ok(), Some(State::Unlocked));
+    }
+
+    #[test]
+    fn test_serve_polls_timers() {
+        let (port, chan) = Chan::new();
+        let handle = spawn(proc(requests) {
+            let mut machine = StateMachine::new(State::Locked, ());
+            machine.add_transition(State::Locked, Event::Coin, State::Unlocked);
+            machine.add_transition(State::Unlocked, Event::Timeout, State::Locked);
+            machine.add_timeout(State::Unlocked, 10, Event::Timeout);
+            serve(&mut machine, requests, |outcome| chan.send(outcome.ok()));
+        });
+
+        assert_eq!(handle.fire(Event::Coin).ok(), Some(State::Unlocked));
+        assert_eq!(port.recv(), Some(State::Locked));
+        assert_eq!(handle.state().ok(), Some(State::Locked));
+        assert!(handle.stop().is_ok());
+    }
+}
@@ -0,0 +1,189 @@
+//! A machine shared by any number of tasks, such as the workers of a pool.
+//! The machine lives in a task of its own and every other task talks to it
+//! through a cloned `runner::Handle`, so events are processed one at a time
+//! in the order they arrive: each one sees the effects of every event
+//! processed before it, and a `state` query made after `fire` returns sees
+//! the transition it took.
+//!
+//! Closures cannot be sent to another task, so the machine is built in its
+//! own task, and the code reacting to the machine is given as `Callbacks`,
+//! which are sent along with it. `spawn` declares the transitions with a
+//! `proc`. Guards, actions and hooks that capture state are closures, which
+//! only live as long as the code that creates them, so machines that need
+//! them are built in the body given to `runner::spawn` and handed to `serve`:
+//!
+//! ```rust
+//! let handle = runner::spawn(proc(requests) {
+//!     let limit = 100;
+//!     let mut machine = StateMachine::new(State::Counting, 0u);
+//!     machine.add_guarded_transition(State::Counting, Event::Tick,
+//!                                    State::Counting, |count| *count < limit);
+//!     machine.on_exit(State::Counting, |count| println!("{}", *count));
+//!     shared::serve(machine, requests, ~Counter);
+//! });
+//! ```
+
+use std::cell::RefCell;
+
+use Definition;
+use StateMachine;
+use Transition;
+use TransitionError;
+use runner;
+use runner::{Handle, Request};
+
+/// Code run in the machine's task as the machine moves. Every method does
+/// nothing unless overridden.
+pub trait Callbacks<S, E, C>: Send {
+    /// Called for every transition taken, after its own actions.
+    fn on_transition(&mut self, _transition: &Transition<S, E>, _context: &mut C) {
+    }
+
+    /// Called once the machine has finished in the final `state`.
+    fn on_complete(&mut self, _state: &S, _context: &mut C) {
+    }
+
+    /// Called with the outcome of every event fired by a timeout declared
+    /// with `Definition::add_timeout`.
+    fn on_timer(&mut self, _outcome: &Result<S, TransitionError<S, E>>) {
+    }
+}
+
+/// Callbacks that do nothing.
+pub struct NoCallbacks;
+
+impl<S, E, C> Callbacks<S, E, C> for NoCallbacks {
+}
+
+/// Spawn a machine starting in `initial` with `context`, whose transitions
+/// are declared by `declare`, and return a handle to share it. The handle
+/// can be cloned and sent to as many tasks as needed.
+pub fn spawn<S: Send + Eq + Clone, E: Send + Eq + Clone, C: Send>(
+        initial: S, declare: proc(&mut Definition<S, E, C>), context: C,
+        callbacks: ~Callbacks<S, E, C>) -> Handle<S, E> {
+    runner::spawn(proc(requests) {
+        let mut definition = Definition::new(initial);
+        declare(&mut definition);
+        serve(StateMachine::from_definition(definition, context), requests, callbacks);
+    })
+}
+
+/// Process `requests` against `machine`, as `runner::serve` does, calling
+/// `callbacks` as the machine moves. Meant for the body given to
+/// `runner::spawn`, once the machine's guards, actions and hooks are
+/// declared.
+pub fn serve<'a, S: Send + Eq + Clone, E: Send + Eq + Clone, C>(
+        machine: StateMachine<'a, S, E, C>, requests: Port<Request<S, E>>,
+        callbacks: ~Callbacks<S, E, C>) {
+    let callbacks = RefCell::new(callbacks);
+    let mut machine = machine;
+    machine.on_transition(|transition, context| {
+        callbacks.with_mut(|callbacks| callbacks.on_transition(transition, context));
+    });
+    machine.on_complete(|state, context| {
+        callbacks.with_mut(|callbacks| callbacks.on_complete(state, context));
+    });
+    runner::serve(&mut machine, requests, |outcome| {
+        callbacks.with_mut(|callbacks| callbacks.on_timer(&outcome));
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::task;
+
+    use Definition;
+    use StateMachine;
+    use Transition;
+    use runner;
+    use super::{spawn, serve, Callbacks};
+
+    defstates! (State -> Counting, Done)
+    defstates! (Event -> Tick, Stop)
+
+    struct Counter {
+        total: Chan<uint>
+    }
+
+    impl Callbacks<State::State, Event::State, uint> for Counter {
+        fn on_transition(&mut self, transition: &Transition<State::State, Event::State>,
+                         count: &mut uint) {
+            if transition.event == Event::Tick {
+                *count += 1;
+            }
+        }
+
+        fn on_complete(&mut self, _: &State::State, count: &mut uint) {
+            self.total.send(*count);
+        }
+    }
+
+    #[test]
+    fn test_shared_machine() {
+        let (total, chan) = Chan::new();
+        let handle = spawn(State::Counting, proc(def) {
+            def.add_transition(State::Counting, Event::Tick, State::Counting);
+            def.add_transition(State::Counting, Event::Stop, State::Done);
+            def.add_final(State::Done);
+        }, 0u, ~Counter { total: chan });
+
+        let (done, finished) = SharedChan::new();
+        for _ in range(0, 4) {
+            let worker = handle.clone();
+            let finished = finished.clone();
+            task::spawn(proc() {
+                for _ in range(0, 10) {
+                    assert!(worker.fire(Event::Tick).is_ok());
+                }
+                finished.send(());
+            });
+        }
+        for _ in range(0, 4) {
+            done.recv();
+        }
+
+        assert_eq!(handle.fire(Event::Stop).ok(), Some(State::Done));
+        assert_eq!(total.recv(), 40);
+        assert!(handle.fire(Event::Tick).is_err());
+        handle.stop();
+    }
+
+    #[test]
+    fn test_shared_guard() {
+        let (total, chan) = Chan::new();
+        let handle = runner::spawn(proc(requests) {
+            let limit = 25u;
+            let mut def = Definition::new(State::Counting);
+            def.add_guarded_transition(State::Counting, Event::Tick, State::Counting,
+                                       |count| *count < limit);
+            def.add_transition(State::Counting, Event::Stop, State::Done);
+            def.add_final(State::Done);
+            serve(StateMachine::from_definition(def, 0u), requests,
+                  ~Counter { total: chan });
+        });
+
+        let (accepted, chan) = SharedChan::new();
+        for _ in range(0, 4) {
+            let worker = handle.clone();
+            let chan = chan.clone();
+            task::spawn(proc() {
+                let mut ticks = 0u;
+                for _ in range(0, 10) {
+                    if worker.fire(Event::Tick).is_ok() {
+                        ticks += 1;
+                    }
+                }
+                chan.send(ticks);
+            });
+        }
+        let mut ticks = 0;
+        for _ in range(0, 4) {
+            ticks += accepted.recv();
+        }
+        assert_eq!(ticks, 25);
+
+        assert_eq!(handle.fire(Event::Stop).ok(), Some(State::Done));
+        assert_eq!(total.recv(), 25);
+        assert!(handle.stop().is_ok());
+    }
+}
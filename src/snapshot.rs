@@ -0,0 +1,382 @@
+//! Persist where a running machine is, so it can be picked up again after a
+//! restart. A `Snapshot` holds the current configuration, the context, what
+//! history pseudo-states remember and the recorded history, but none of the
+//! closures: those belong to the `Definition` the snapshot is restored onto
+//! and to the hooks registered afterwards.
+//!
+//! `Snapshot` derives `Encodable` and `Decodable`, so it can be written with
+//! any `extra::serialize` encoder, such as `extra::json::Encoder`.
+
+use Deep;
+use Definition;
+use Shallow;
+use StateMachine;
+use history::History;
+
+/// What a snapshot remembers of the definition it was taken from, by name,
+/// so it can tell whether another definition can still run it.
+#[deriving(Eq, Clone, Encodable, Decodable)]
+pub struct Fingerprint {
+    initial: ~str,
+    states: ~[~str],
+    /// How every state fits in the definition, see `shape`
+    shapes: ~[~str],
+    events: ~[~str],
+    /// Every transition, written as `From + Event => To`
+    transitions: ~[~str]
+}
+
+impl Fingerprint {
+    /// Describe `definition`.
+    pub fn of<'a, S: Eq + Clone + ToStr, E: Eq + Clone + ToStr, C>(
+            definition: &Definition<'a, S, E, C>) -> Fingerprint {
+        Fingerprint {
+            initial: definition.initial().to_str(),
+            states: definition.states().iter().map(|s| s.to_str()).collect(),
+            shapes: definition.states().iter().map(|s| shape(definition, s)).collect(),
+            events: definition.events().iter().map(|e| e.to_str()).collect(),
+            transitions: definition.transitions().iter().map(|t| {
+                format!("{} + {} => {}", t.from.to_str(), t.event.to_str(),
+                        t.to.to_str())
+            }).collect()
+        }
+    }
+
+    /// Whether a machine described by `self` can run on a definition
+    /// described by `current`: the initial state is the same, every state,
+    /// event and transition is still there, and every state still has the
+    /// same parent, initial substate, regions, history and finality.
+    /// Additions are fine.
+    pub fn is_compatible_with(&self, current: &Fingerprint) -> bool {
+        self.initial == current.initial &&
+            self.states.iter().all(|s| current.states.contains(s)) &&
+            self.shapes.iter().all(|s| current.shapes.contains(s)) &&
+            self.events.iter().all(|e| current.events.contains(e)) &&
+            self.transitions.iter().all(|t| current.transitions.contains(t))
+    }
+}
+
+/// Describe how `state` fits in `definition`, such as `Heating: in Running`
+/// or `Player: starts in Playback, parallel`: the composite state containing
+/// it, the substate it starts in, whether its substates are regions, whether
+/// it is final and which state it is a history pseudo-state of.
+fn shape<'a, S: Eq + Clone + ToStr, E: Eq + Clone, C>(
+        definition: &Definition<'a, S, E, C>, state: &S) -> ~str {
+    let mut parts = ~[];
+    match definition.parent(state) {
+        Some(parent) => parts.push(format!("in {}", parent.to_str())),
+        None => ()
+    }
+    match definition.initial_substate(state) {
+        Some(child) => parts.push(format!("starts in {}", child.to_str())),
+        None => ()
+    }
+    if definition.is_parallel(state) {
+        parts.push(~"parallel");
+    }
+    if definition.is_final(state) {
+        parts.push(~"final");
+    }
+    match definition.history_of(state) {
+        Some((parent, Shallow)) => {
+            parts.push(format!("shallow history of {}", parent.to_str()));
+        }
+        Some((parent, Deep)) => {
+            parts.push(format!("deep history of {}", parent.to_str()));
+        }
+        None => ()
+    }
+    if parts.is_empty() {
+        state.to_str()
+    } else {
+        format!("{}: {}", state.to_str(), parts.connect(", "))
+    }
+}
+
+/// Whether a machine running `definition` can be in the leaf states
+/// `leaves`: exactly one substate is active in every active composite state
+/// and at the top level, and every region of an active parallel state is
+/// active.
+fn fits<'a, S: Eq + Clone, E: Eq + Clone, C>(definition: &Definition<'a, S, E, C>,
+                                             leaves: &[S]) -> bool {
+    if leaves.is_empty() || leaves.iter().any(|s| definition.is_composite(s)) {
+        return false;
+    }
+    let mut active = ~[];
+    for leaf in leaves.iter() {
+        let lineage = definition.lineage(leaf);
+        if active.contains(leaf) {
+            return false;
+        }
+        for state in lineage.move_iter() {
+            if !active.contains(&state) {
+                active.push(state);
+            }
+        }
+    }
+
+    let roots: ~[&S] = active.iter().filter(|s| definition.parent(*s).is_none()).collect();
+    if roots.len() != 1 {
+        return false;
+    }
+    active.iter().filter(|s| definition.is_composite(*s)).all(|parent| {
+        let children = definition.substates(parent);
+        let activeChildren: ~[&S] =
+            children.iter().filter(|s| active.contains(*s)).collect();
+        if definition.is_parallel(parent) {
+            activeChildren.len() == children.len()
+        } else {
+            activeChildren.len() == 1
+        }
+    })
+}
+
+/// The persistent part of a running machine.
+#[deriving(Eq, Clone, Encodable, Decodable)]
+pub struct Snapshot<S, E, C> {
+    /// The definition the snapshot was taken from
+    fingerprint: Fingerprint,
+    /// The active leaf states of the machine, see
+    /// `StateMachine::configuration`
+    configuration: ~[S],
+    /// The context the machine was holding
+    context: C,
+    /// The leaves that were active below each composite state when it was
+    /// last left
+    remembered: ~[(S, ~[S])],
+    /// The transitions the machine had recorded, if it was recording them
+    history: Option<History<S, E>>
+}
+
+/// Why a snapshot could not be restored onto a definition.
+#[deriving(Eq, Clone)]
+pub enum RestoreError {
+    /// The initial state changed, or a state, event or transition known
+    /// when the snapshot was taken no longer exists.
+    DefinitionChanged,
+    /// A state the machine was in is not part of the definition.
+    UnknownState,
+    /// The states the machine was in, or those remembered for a history
+    /// pseudo-state, are not a configuration the definition allows.
+    InvalidConfiguration
+}
+
+impl<'a, S: Eq + Clone + ToStr, E: Eq + Clone + ToStr, C: Clone>
+        StateMachine<'a, S, E, C> {
+
+    /// Capture the current configuration, context, remembered history
+    /// configurations and recorded history of the machine.
+    pub fn snapshot(&self) -> Snapshot<S, E, C> {
+        Snapshot {
+            fingerprint: Fingerprint::of(&self.definition),
+            configuration: self.configuration.clone(),
+            context: self.context.clone(),
+            remembered: self.remembered.clone(),
+            history: self.history.clone()
+        }
+    }
+
+    /// Recreate a machine running `definition` from `snapshot`. Restoring is
+    /// refused if the definition changed incompatibly since the snapshot was
+    /// taken: see `Fingerprint::is_compatible_with`. States, events and
+    /// transitions added since are fine. The configuration has to be made of
+    /// leaf states, one in each active region, and every remembered leaf has
+    /// to lie below the composite state it is remembered for. No hooks are
+    /// run for entering the restored state, but its timeouts start again
+    /// from the moment it is restored.
+    pub fn restore(definition: Definition<'a, S, E, C>, snapshot: Snapshot<S, E, C>)
+            -> Result<StateMachine<'a, S, E, C>, RestoreError> {
+        if !snapshot.fingerprint.is_compatible_with(&Fingerprint::of(&definition)) {
+            return Err(DefinitionChanged);
+        }
+        if snapshot.configuration.is_empty() ||
+           snapshot.configuration.iter().any(|s| !definition.states.contains(s)) {
+            return Err(UnknownState);
+        }
+        if !fits(&definition, snapshot.configuration.as_slice()) {
+            return Err(InvalidConfiguration);
+        }
+        for pair in snapshot.remembered.iter() {
+            let (ref parent, ref leaves) = *pair;
+            let consistent = definition.is_composite(parent) && !leaves.is_empty() &&
+                leaves.iter().all(|leaf| {
+                    definition.states.contains(leaf) && !definition.is_composite(leaf) &&
+                        definition.child_towards(parent, leaf).is_some()
+                });
+            if !consistent {
+                return Err(InvalidConfiguration);
+            }
+        }
+
+        let initialState = definition.initial().clone();
+        let mut machine = StateMachine::resume(definition, initialState,
+                                               snapshot.context);
+        machine.configuration = snapshot.configuration;
+        machine.remembered = snapshot.remembered;
+        machine.history = snapshot.history;
+        machine.restart_clock();
+        Ok(machine)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::mem::MemWriter;
+    use std::str;
+    use extra::json;
+    use extra::serialize::{Encodable, Decodable};
+
+    use Definition;
+    use StateMachine;
+    use clock::{Clock, ManualClock};
+    use history::History;
+    use loader::{load_definition, DynState, DynEvent};
+    use super::{Snapshot, DefinitionChanged, UnknownState, InvalidConfiguration};
+
+    static ORDER: &'static str = "{
+        \"states\": [\"Placed\", \"Paid\", \"Shipped\"],
+        \"initial\": \"Placed\",
+        \"transitions\": [
+            {\"from\": \"Placed\", \"event\": \"pay\", \"to\": \"Paid\"},
+            {\"from\": \"Paid\", \"event\": \"ship\", \"to\": \"Shipped\"}
+        ]
+    }";
+
+    defstates! (Phase -> Idle, Running, Slow, Fast, Resume, Done)
+    defstates! (Step -> Start, Speed, Pause, Back)
+
+    fn phases<'a>(deep: bool) -> Definition<'a, Phase::State, Step::State, ()> {
+        let mut def = Definition::new(Phase::Idle);
+        def.add_substate(Phase::Running, Phase::Slow);
+        def.add_substate(Phase::Running, Phase::Fast);
+        if deep {
+            def.add_deep_history(Phase::Running, Phase::Resume);
+        } else {
+            def.add_shallow_history(Phase::Running, Phase::Resume);
+        }
+        def.add_transition(Phase::Idle, Step::Start, Phase::Running);
+        def.add_transition(Phase::Slow, Step::Speed, Phase::Fast);
+        def.add_transition(Phase::Running, Step::Pause, Phase::Idle);
+        def.add_transition(Phase::Idle, Step::Back, Phase::Resume);
+        def
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut sm = StateMachine::from_definition(load_definition(ORDER).unwrap(), 7);
+        sm.record_history(History::unbounded());
+        assert!(sm.fire(DynEvent::new("pay")).is_ok());
+
+        let mut writer = MemWriter::new();
+        sm.snapshot().encode(&mut json::Encoder::new(&mut writer as &mut Writer));
+        let encoded = str::from_utf8_owned(writer.inner());
+
+        let mut decoder = json::Decoder::new(json::from_str(encoded).unwrap());
+        let snapshot: Snapshot<DynState, DynEvent, int> =
+            Decodable::decode(&mut decoder);
+        assert_eq!(snapshot, sm.snapshot());
+
+        let mut restored = StateMachine::restore(load_definition(ORDER).unwrap(),
+                                                 snapshot).unwrap();
+        assert_eq!(*restored.state(), DynState::new("Paid"));
+        assert_eq!(*restored.context(), 7);
+        assert!(restored.fire(DynEvent::new("ship")).is_ok());
+        assert_eq!(restored.history().len(), 2);
+        assert_eq!(restored.history()[1].sequence, 1);
+    }
+
+    #[test]
+    fn test_restore_changed_definition() {
+        let sm = StateMachine::from_definition(load_definition(ORDER).unwrap(), ());
+        let renamed = ORDER.replace("Shipped", "Sent");
+        let err = StateMachine::restore(load_definition(renamed).unwrap(),
+                                        sm.snapshot()).err();
+        assert_eq!(err, Some(DefinitionChanged));
+
+        let changes = [ORDER.replace("\"ship\"", "\"send\""),
+                       ORDER.replace("\"to\": \"Shipped\"", "\"to\": \"Placed\""),
+                       ORDER.replace("\"initial\": \"Placed\"", "\"initial\": \"Paid\"")];
+        for changed in changes.iter() {
+            let err = StateMachine::restore(load_definition(*changed).unwrap(),
+                                            sm.snapshot()).err();
+            assert_eq!(err, Some(DefinitionChanged));
+        }
+
+        let extended = ORDER.replace("\"Shipped\"]", "\"Shipped\", \"Lost\"]");
+        assert!(StateMachine::restore(load_definition(extended).unwrap(),
+                                      sm.snapshot()).is_ok());
+
+        let mut snapshot = sm.snapshot();
+        snapshot.configuration = ~[DynState::new("Lost")];
+        let err = StateMachine::restore(load_definition(ORDER).unwrap(),
+                                        snapshot).err();
+        assert_eq!(err, Some(UnknownState));
+    }
+
+    #[test]
+    fn test_restore_rearms_timeouts() {
+        let mut def = load_definition(ORDER).unwrap();
+        def.add_timeout(DynState::new("Paid"), 1000, DynEvent::new("ship"));
+        let mut sm = StateMachine::from_definition(def, ());
+        assert!(sm.fire(DynEvent::new("pay")).is_ok());
+
+        let mut def = load_definition(ORDER).unwrap();
+        def.add_timeout(DynState::new("Paid"), 1000, DynEvent::new("ship"));
+        let clock = ManualClock::new(0);
+        let mut restored = StateMachine::restore(def, sm.snapshot()).unwrap();
+        assert!(restored.time_in(&DynState::new("Placed")).is_none());
+        assert!(restored.time_in(&DynState::new("Paid")).is_some());
+        assert!(restored.next_deadline().is_some());
+
+        restored.set_clock(&clock as &Clock);
+        assert_eq!(restored.time_in(&DynState::new("Paid")), Some(0));
+        assert_eq!(restored.next_deadline(), Some(1000));
+
+        clock.set(1000);
+        let results: ~[Option<DynState>] =
+            restored.poll().move_iter().map(|result| result.ok()).collect();
+        assert_eq!(results, ~[Some(DynState::new("Shipped"))]);
+    }
+
+    #[test]
+    fn test_restore_changed_structure() {
+        let mut sm = StateMachine::from_definition(phases(false), ());
+        assert!(sm.fire(Step::Start).is_ok());
+        assert!(sm.fire(Step::Speed).is_ok());
+        assert!(sm.fire(Step::Pause).is_ok());
+
+        let mut nested = phases(false);
+        nested.add_substate(Phase::Fast, Phase::Done);
+        let mut ending = phases(false);
+        ending.add_final(Phase::Idle);
+        for def in (~[nested, phases(true), ending]).move_iter() {
+            let err = StateMachine::restore(def, sm.snapshot()).err();
+            assert_eq!(err, Some(DefinitionChanged));
+        }
+
+        let mut extended = phases(false);
+        extended.add_substate(Phase::Running, Phase::Done);
+        let mut restored = StateMachine::restore(extended, sm.snapshot()).unwrap();
+        assert_eq!(restored.fire(Step::Back).ok(), Some(Phase::Fast));
+    }
+
+    #[test]
+    fn test_restore_invalid_configuration() {
+        let mut sm = StateMachine::from_definition(phases(false), ());
+        assert!(sm.fire(Step::Start).is_ok());
+
+        let configurations = [~[Phase::Running], ~[Phase::Slow, Phase::Fast],
+                              ~[Phase::Slow, Phase::Idle]];
+        for configuration in configurations.iter() {
+            let mut snapshot = sm.snapshot();
+            snapshot.configuration = configuration.clone();
+            let err = StateMachine::restore(phases(false), snapshot).err();
+            assert_eq!(err, Some(InvalidConfiguration));
+        }
+
+        let mut snapshot = sm.snapshot();
+        snapshot.remembered = ~[(Phase::Running, ~[Phase::Idle])];
+        let err = StateMachine::restore(phases(false), snapshot).err();
+        assert_eq!(err, Some(InvalidConfiguration));
+    }
+}
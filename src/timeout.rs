@@ -0,0 +1,181 @@
+//! Timed transitions. A definition can declare that a state fires an event
+//! once the machine has spent some time in it, and a machine can be asked to
+//! fire an event after a delay. Both are timers that are cancelled as soon
+//! as the state they belong to is left.
+//!
+//! Nothing runs in the background: whoever drives the machine calls `.poll`
+//! to fire the timers that are due, for instance after sleeping until
+//! `.next_deadline`. Time is read from the machine's `Clock`.
+
+use Definition;
+use StateMachine;
+use TransitionError;
+
+/// An event waiting to be fired while the machine stays in `state`.
+pub struct Timer<S, E> {
+    state: S,
+    /// When the event is due, according to the machine's clock
+    due: u64,
+    event: E,
+    /// Whether the timer was armed before the current `poll` started; the
+    /// ones armed while polling wait for the next call
+    ready: bool
+}
+
+impl<'a, S: Eq + Clone, E: Eq + Clone, C> Definition<'a, S, E, C> {
+
+    /// Fire `event` once the machine has been in `state` for `after`
+    /// milliseconds without leaving it. The time spent in `state` starts
+    /// again every time it is entered.
+    pub fn add_timeout(&mut self, state: S, after: u64, event: E) {
+        self.add_state(state.clone());
+        self.add_event(event.clone());
+        self.timeouts.push((state, after, event));
+    }
+}
+
+impl<'a, S: Eq + Clone, E: Eq + Clone, C> StateMachine<'a, S, E, C> {
+
+    /// Declare a timeout. See `Definition::add_timeout`. Only states entered
+    /// afterwards are affected.
+    pub fn add_timeout(&mut self, state: S, after: u64, event: E) {
+        self.definition.add_timeout(state, after, event);
+    }
+
+    /// Fire `event` once `delay` milliseconds have passed, unless the machine
+    /// leaves its current state first.
+    pub fn fire_after(&mut self, event: E, delay: u64) {
+        let timer = Timer {
+            state: self.state().clone(),
+            due: self.clock.now() + delay,
+            event: event,
+            ready: false
+        };
+        self.timers.push(timer);
+    }
+
+    /// When the earliest pending timer is due, if there is one.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.timers.iter().map(|timer| timer.due).min()
+    }
+
+    /// Fire every timer that is due, earliest first, and return the outcome
+    /// of each. Timers cancelled by the transitions taken along the way are
+    /// not fired, and timers armed along the way, such as the timeouts of the
+    /// states entered, wait for the next call even if they are already due,
+    /// so zero timeouts cannot keep a single call going forever.
+    pub fn poll(&mut self) -> ~[Result<S, TransitionError<S, E>>] {
+        for timer in self.timers.mut_iter() {
+            timer.ready = true;
+        }
+        let mut results = ~[];
+        loop {
+            let now = self.clock.now();
+            let mut due: Option<uint> = None;
+            for (index, timer) in self.timers.iter().enumerate() {
+                let earlier = match due {
+                    Some(other) => timer.due < self.timers[other].due,
+                    None => true
+                };
+                if timer.ready && timer.due <= now && earlier {
+                    due = Some(index);
+                }
+            }
+
+            let timer = match due {
+                Some(index) => self.timers.remove(index),
+                None => return results
+            };
+            results.push(self.fire(timer.event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Definition;
+    use StateMachine;
+    use clock::{Clock, ManualClock};
+
+    defstates! (State -> Idle, Connecting, Connected, Failed)
+    defstates! (Event -> Connect, Established, Timeout, Retry)
+
+    fn connection<'a>() -> Definition<'a, State::State, Event::State, ()> {
+        let mut def = Definition::new(State::Idle);
+        def.add_transition(State::Idle, Event::Connect, State::Connecting);
+        def.add_transition(State::Connecting, Event::Established, State::Connected);
+        def.add_transition(State::Connecting, Event::Timeout, State::Failed);
+        def.add_transition(State::Failed, Event::Retry, State::Connecting);
+        def.add_timeout(State::Connecting, 30000, Event::Timeout);
+        def
+    }
+
+    #[test]
+    fn test_timeout() {
+        let clock = ManualClock::new(0);
+        let mut sm = StateMachine::from_definition(connection(), ());
+        sm.set_clock(&clock as &Clock);
+        assert!(sm.next_deadline().is_none());
+
+        assert!(sm.fire(Event::Connect).is_ok());
+        assert_eq!(sm.next_deadline(), Some(30000));
+        clock.set(29999);
+        assert!(sm.poll().is_empty());
+
+        clock.set(30000);
+        let results: ~[Option<State::State>] =
+            sm.poll().move_iter().map(|result| result.ok()).collect();
+        assert_eq!(results, ~[Some(State::Failed)]);
+        assert_eq!(*sm.state(), State::Failed);
+        assert!(sm.next_deadline().is_none());
+    }
+
+    #[test]
+    fn test_timeout_cancelled() {
+        let clock = ManualClock::new(0);
+        let mut sm = StateMachine::from_definition(connection(), ());
+        sm.set_clock(&clock as &Clock);
+
+        assert!(sm.fire(Event::Connect).is_ok());
+        clock.set(10000);
+        assert!(sm.fire(Event::Established).is_ok());
+        clock.set(60000);
+        assert!(sm.poll().is_empty());
+        assert_eq!(*sm.state(), State::Connected);
+    }
+
+    #[test]
+    fn test_fire_after() {
+        let clock = ManualClock::new(0);
+        let mut sm = StateMachine::from_definition(connection(), ());
+        sm.set_clock(&clock as &Clock);
+
+        sm.fire_after(Event::Connect, 500);
+        clock.set(500);
+        assert_eq!(sm.poll().len(), 1);
+        assert_eq!(*sm.state(), State::Connecting);
+
+        // Leaving Connecting cancels the delayed event as well as the timeout.
+        sm.fire_after(Event::Established, 100);
+        assert!(sm.fire(Event::Timeout).is_ok());
+        clock.set(100000);
+        assert!(sm.poll().is_empty());
+    }
+
+    #[test]
+    fn test_zero_timeouts() {
+        let clock = ManualClock::new(0);
+        let mut def = Definition::new(State::Connecting);
+        def.add_transition(State::Connecting, Event::Timeout, State::Failed);
+        def.add_transition(State::Failed, Event::Retry, State::Connecting);
+        def.add_timeout(State::Connecting, 0, Event::Timeout);
+        def.add_timeout(State::Failed, 0, Event::Retry);
+        let mut sm = StateMachine::from_definition(def, ());
+        sm.set_clock(&clock as &Clock);
+
+        assert_eq!(sm.poll().len(), 1);
+        assert_eq!(*sm.state(), State::Failed);
+        assert_eq!(sm.poll().len(), 1);
+        assert_eq!(*sm.state(), State::Connecting);
+    }
+}